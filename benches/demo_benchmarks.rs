@@ -33,7 +33,7 @@ fn bench_hello_world(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -48,7 +48,7 @@ fn bench_if_conditions(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -63,7 +63,7 @@ fn bench_nested_expressions(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -78,7 +78,7 @@ fn bench_recursion_small(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -100,7 +100,7 @@ fn bench_fibonacci_10(c: &mut Criterion) {
         })
         .collect();
 
-      func.syntax = std::rc::Rc::new(new_syntax);
+      func.syntax = calx_vm::rc::Rc::new(new_syntax);
       break;
     }
   }
@@ -112,7 +112,7 @@ fn bench_fibonacci_10(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -127,7 +127,7 @@ fn bench_assertions(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
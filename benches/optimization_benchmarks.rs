@@ -1,6 +1,8 @@
-use calx_vm::{Calx, CalxFunc, CalxImportsDict, CalxSyntax, CalxType, CalxVM};
+use calx_vm::rc::Rc;
+use calx_vm::{fuse_instrs, parse_function, Calx, CalxEngine, CalxFunc, CalxImportsDict, CalxInlineHint, CalxSyntax, CalxType, CalxVM};
+use cirru_parser::{parse, Cirru};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use std::{collections::HashMap, rc::Rc};
+use std::collections::HashMap;
 
 // Create an arithmetic-intensive function to test optimization effects
 fn create_arithmetic_intensive_func() -> CalxFunc {
@@ -24,6 +26,9 @@ fn create_arithmetic_intensive_func() -> CalxFunc {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec!["x".to_string()]),
   }
 }
@@ -50,6 +55,9 @@ fn create_stack_intensive_func() -> CalxFunc {
       CalxSyntax::Return,              // Return x+1
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec!["x".to_string()]),
   }
 }
@@ -83,6 +91,9 @@ fn create_locals_intensive_func() -> CalxFunc {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec![
       "param".to_string(),
       "temp1".to_string(),
@@ -116,6 +127,9 @@ fn create_const_intensive_func() -> CalxFunc {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec![]),
   }
 }
@@ -132,6 +146,9 @@ fn create_main_func(target_func: &str, args: Vec<CalxSyntax>) -> CalxFunc {
     ret_types: Rc::new(vec![CalxType::I64]),
     syntax: Rc::new(syntax),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec![]),
   }
 }
@@ -148,7 +165,7 @@ fn bench_arithmetic_intensive(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -165,7 +182,7 @@ fn bench_stack_intensive(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -182,7 +199,7 @@ fn bench_locals_intensive(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -199,7 +216,7 @@ fn bench_const_intensive(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -227,6 +244,9 @@ fn bench_mixed_operations(c: &mut Criterion) {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec!["param".to_string(), "temp".to_string()]),
   };
 
@@ -239,17 +259,191 @@ fn bench_mixed_operations(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
 
+// Loop and recursion code with sequences recognized by the peephole pass
+const PEEPHOLE_CODE: &str = r#"
+fn main (-> i64)
+  call sum
+    const 2000
+  call fibo
+    const 16
+  i.add
+  return
+
+fn sum (($n i64) -> i64)
+  local.new
+  local.new
+  const 0
+  local.set 1
+  const 0
+  local.set 2
+  block (->)
+    loop (->)
+      local.get 2
+      const 2000
+      i.gt
+      br-if 1
+      local.get 1
+      local.get 2
+      i.add
+      local.set 1
+      local.get 2
+      const 1
+      i.add
+      local.set 2
+      br 0
+  local.get 1
+  return
+
+fn fibo (($x i64) -> i64)
+  local.get $x
+  const 3
+  i.lt
+  if (->)
+    do
+      const 1
+      return
+    do
+      call fibo
+        i.add (local.get $x) (const -1)
+      call fibo
+        i.add (local.get $x) (const -2)
+      i.add
+      return
+"#;
+
+// Preprocess once, optionally with fused instructions, so only running is measured
+fn prepare_peephole_vm(fused: bool) -> CalxVM {
+  prepare_engine_vm(fused, CalxEngine::Stack)
+}
+
+fn prepare_engine_vm(fused: bool, engine: CalxEngine) -> CalxVM {
+  let mut funcs = vec![];
+  for x in parse(PEEPHOLE_CODE).unwrap() {
+    if let Cirru::List(ys) = x {
+      funcs.push(parse_function(&ys).unwrap());
+    }
+  }
+  let mut vm = CalxVM::new_with_engine(funcs, vec![], HashMap::new(), engine);
+  vm.preprocess(false).unwrap();
+  if fused {
+    for f in &mut vm.funcs {
+      fuse_instrs(f);
+    }
+  }
+  vm.setup_top_frame().unwrap();
+  vm
+}
+
+// Benchmark: same program with and without peephole fusion
+fn bench_peephole(c: &mut Criterion) {
+  let mut group = c.benchmark_group("peephole");
+  for (name, fused) in [("unfused", false), ("fused", true)] {
+    let vm = prepare_peephole_vm(fused);
+    group.bench_function(name, |b| {
+      b.iter(|| {
+        let mut vm = vm.clone();
+        black_box(vm.run(vec![]).into_result().unwrap());
+      })
+    });
+  }
+  group.finish();
+}
+
+// Benchmark: same program with stack engine and register engine
+fn bench_engines(c: &mut Criterion) {
+  let mut group = c.benchmark_group("engines");
+  for (name, engine) in [("stack", CalxEngine::Stack), ("register", CalxEngine::Register)] {
+    let vm = prepare_engine_vm(false, engine);
+    group.bench_function(name, |b| {
+      b.iter(|| {
+        let mut vm = vm.clone();
+        black_box(vm.run(vec![]).into_result().unwrap());
+      })
+    });
+  }
+  group.finish();
+}
+
+const IMPORT_CALLS_CODE: &str = r#"
+fn main (-> i64)
+  local.new $i
+  const 0
+  local.set $i
+  block (->)
+    loop (->)
+      local.get $i
+      call-import tick
+      drop
+      local.get $i
+      const 1
+      i.add
+      local.set $i
+      local.get $i
+      const 1000
+      i.lt
+      br-if 0
+  local.get $i
+  return
+"#;
+
+#[allow(clippy::ptr_arg, clippy::result_large_err)]
+fn tick(xs: &Vec<Calx>) -> Result<Calx, calx_vm::CalxError> {
+  Ok(xs[0].to_owned())
+}
+
+// Benchmark: host calls in a loop, with other imports registered
+fn bench_import_calls(c: &mut Criterion) {
+  let mut funcs = vec![];
+  for x in parse(IMPORT_CALLS_CODE).unwrap() {
+    if let Cirru::List(ys) = x {
+      funcs.push(parse_function(&ys).unwrap());
+    }
+  }
+  let mut imports: CalxImportsDict = HashMap::new();
+  for name in ["log", "log2", "log3", "tick", "trace", "warn"] {
+    imports.insert(Rc::from(name), (tick, 1));
+  }
+  let mut vm = CalxVM::new(funcs, vec![], imports);
+  vm.preprocess(false).unwrap();
+  vm.setup_top_frame().unwrap();
+
+  c.bench_function("import_calls", |b| {
+    b.iter(|| {
+      let mut vm = vm.clone();
+      black_box(vm.run(vec![]).into_result().unwrap());
+    })
+  });
+}
+
+// Benchmark: repeated `call` of a small function on one VM, lowering for register engine is reused
+fn bench_repeated_calls(c: &mut Criterion) {
+  let mut group = c.benchmark_group("repeated_calls");
+  for (name, engine) in [("stack", CalxEngine::Stack), ("register", CalxEngine::Register)] {
+    let mut vm = prepare_engine_vm(false, engine);
+    group.bench_function(name, |b| {
+      b.iter(|| {
+        black_box(vm.call("fibo", &[Calx::I64(5)]).unwrap());
+      })
+    });
+  }
+  group.finish();
+}
+
 criterion_group!(
   optimization_benches,
   bench_arithmetic_intensive,
   bench_stack_intensive,
   bench_locals_intensive,
   bench_const_intensive,
-  bench_mixed_operations
+  bench_mixed_operations,
+  bench_peephole,
+  bench_engines,
+  bench_import_calls,
+  bench_repeated_calls
 );
 criterion_main!(optimization_benches);
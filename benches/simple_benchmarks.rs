@@ -1,6 +1,7 @@
-use calx_vm::{Calx, CalxFunc, CalxImportsDict, CalxSyntax, CalxType, CalxVM};
+use calx_vm::rc::Rc;
+use calx_vm::{Calx, CalxFunc, CalxImportsDict, CalxInlineHint, CalxSyntax, CalxType, CalxVM};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use std::{collections::HashMap, rc::Rc};
+use std::collections::HashMap;
 
 // Simple addition function
 fn create_simple_add_func() -> CalxFunc {
@@ -15,6 +16,9 @@ fn create_simple_add_func() -> CalxFunc {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec!["a".to_string(), "b".to_string()]),
   }
 }
@@ -35,6 +39,9 @@ fn create_complex_arithmetic_func() -> CalxFunc {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec!["a".to_string(), "b".to_string(), "c".to_string()]),
   }
 }
@@ -60,6 +67,9 @@ fn create_stack_ops_func() -> CalxFunc {
       CalxSyntax::Return,              // Return x+1
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec!["x".to_string()]),
   }
 }
@@ -86,6 +96,9 @@ fn create_local_vars_func() -> CalxFunc {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec!["param".to_string(), "temp".to_string()]),
   }
 }
@@ -102,6 +115,9 @@ fn create_main_func(target_func: &str, args: Vec<CalxSyntax>) -> CalxFunc {
     ret_types: Rc::new(vec![CalxType::I64]),
     syntax: Rc::new(syntax),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec![]),
   }
 }
@@ -121,7 +137,7 @@ fn bench_simple_add(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -145,7 +161,7 @@ fn bench_complex_arithmetic(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -162,7 +178,7 @@ fn bench_stack_operations(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -179,7 +195,7 @@ fn bench_local_variables(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -217,7 +233,7 @@ fn bench_instruction_execution(c: &mut Criterion) {
   c.bench_function("instruction_execution", |b| {
     b.iter(|| {
       let mut vm_clone = vm.clone();
-      black_box(vm_clone.run(vec![]).unwrap());
+      black_box(vm_clone.run(vec![]).into_result().unwrap());
     })
   });
 }
@@ -241,6 +257,9 @@ fn bench_multiple_calls(c: &mut Criterion) {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec![]),
   };
 
@@ -253,7 +272,7 @@ fn bench_multiple_calls(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
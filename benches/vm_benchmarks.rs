@@ -1,6 +1,7 @@
-use calx_vm::{Calx, CalxFunc, CalxSyntax, CalxType, CalxVM};
+use calx_vm::rc::Rc;
+use calx_vm::{Calx, CalxFunc, CalxInlineHint, CalxSyntax, CalxType, CalxVM};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use std::{collections::HashMap, rc::Rc};
+use std::collections::HashMap;
 
 // Create a simple arithmetic function
 fn create_arithmetic_func() -> CalxFunc {
@@ -17,6 +18,9 @@ fn create_arithmetic_func() -> CalxFunc {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec!["a".to_string(), "b".to_string()]),
   }
 }
@@ -65,6 +69,9 @@ fn create_sum_func() -> CalxFunc {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec!["n".to_string(), "sum".to_string(), "i".to_string()]),
   }
 }
@@ -107,6 +114,9 @@ fn create_fibonacci_func() -> CalxFunc {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec!["n".to_string()]),
   }
 }
@@ -119,6 +129,9 @@ fn create_main_func(call_target: &str) -> CalxFunc {
     ret_types: Rc::new(vec![CalxType::I64]),
     syntax: Rc::new(vec![CalxSyntax::Call(Rc::from(call_target)), CalxSyntax::Return]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec![]),
   }
 }
@@ -135,7 +148,7 @@ fn bench_arithmetic(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![Calx::I64(10), Calx::I64(20)]).unwrap());
+      black_box(vm.run(vec![Calx::I64(10), Calx::I64(20)]).into_result().unwrap());
     })
   });
 }
@@ -152,7 +165,7 @@ fn bench_sum_loop(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![Calx::I64(1000)]).unwrap());
+      black_box(vm.run(vec![Calx::I64(1000)]).into_result().unwrap());
     })
   });
 }
@@ -169,7 +182,7 @@ fn bench_fibonacci_recursive(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![Calx::I64(20)]).unwrap());
+      black_box(vm.run(vec![Calx::I64(20)]).into_result().unwrap());
     })
   });
 }
@@ -195,6 +208,9 @@ fn bench_stack_operations(c: &mut Criterion) {
       CalxSyntax::Return,
     ]),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
     local_names: Rc::new(vec![]),
   };
 
@@ -207,7 +223,7 @@ fn bench_stack_operations(c: &mut Criterion) {
       let mut vm = CalxVM::new(funcs.clone(), vec![], imports.clone());
       vm.preprocess(false).unwrap();
       vm.setup_top_frame().unwrap();
-      black_box(vm.run(vec![]).unwrap());
+      black_box(vm.run(vec![]).into_result().unwrap());
     })
   });
 }
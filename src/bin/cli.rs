@@ -1,11 +1,15 @@
+use calx_vm::rc::Rc;
+use std::collections::hash_map::HashMap;
 use std::fs;
-use std::time::Instant;
-use std::{collections::hash_map::HashMap, rc::Rc};
+use std::time::{Duration, Instant};
 
 use argh::FromArgs;
 use cirru_parser::{parse, Cirru};
 
-use calx_vm::{log_calx_value, parse_function, Calx, CalxFunc, CalxImportsDict, CalxVM};
+use calx_vm::{
+  convert_tail_calls, log_calx_value, parse_function, parse_import, Calx, CalxEngine, CalxFuelOutcome, CalxFunc, CalxImportDecl,
+  CalxImportsDict, CalxPassManager, CalxRunOutcome, CalxVM,
+};
 
 // #[cfg(not(target_env = "msvc"))]
 // use tikv_jemallocator::Jemalloc;
@@ -38,13 +42,52 @@ struct TopLevel {
   /// eval binary
   #[argh(switch, short = 'e')]
   eval_binary: bool,
+  /// rewrite calls in tail position into return-call, and list recursive calls not converted
+  #[argh(switch)]
+  tail_calls: bool,
+  /// run with register engine
+  #[argh(switch)]
+  register: bool,
+  /// optimization level from 0 to 3, also accepted as `-O2`
+  #[argh(option, short = 'O', default = "0")]
+  opt_level: u8,
+  /// print functions after a pass, by name or `all`
+  #[argh(option)]
+  print_after: Vec<String>,
+  /// stop running when instructions cost more fuel than this
+  #[argh(option)]
+  fuel: Option<u64>,
+  /// stop running after milliseconds
+  #[argh(option)]
+  timeout: Option<u64>,
   /// source
   #[argh(positional)]
   source: String,
 }
 
+/// like `argh::from_env`, but also takes `-O2` as `-O 2`
+fn args_from_env() -> TopLevel {
+  let strings: Vec<String> = std::env::args()
+    .flat_map(|x| match x.strip_prefix("-O") {
+      Some(level) if !level.is_empty() => vec![String::from("-O"), level.to_owned()],
+      _ => vec![x],
+    })
+    .collect();
+  let strs: Vec<&str> = strings.iter().map(|x| x.as_str()).collect();
+  TopLevel::from_args(&strs[..1], &strs[1..]).unwrap_or_else(|early_exit| match early_exit.status {
+    Ok(()) => {
+      println!("{}", early_exit.output);
+      std::process::exit(0)
+    }
+    Err(()) => {
+      eprintln!("{}\nRun {} --help for more information.", early_exit.output, strs[0]);
+      std::process::exit(1)
+    }
+  })
+}
+
 fn main() -> Result<(), String> {
-  let args: TopLevel = argh::from_env();
+  let args: TopLevel = args_from_env();
 
   let source = args.source;
   let show_code = args.show_code;
@@ -52,6 +95,7 @@ fn main() -> Result<(), String> {
   let eval_binary = args.eval_binary;
 
   let mut fns: Vec<CalxFunc> = vec![];
+  let mut import_decls: Vec<CalxImportDecl> = vec![];
 
   if eval_binary {
     todo!()
@@ -74,6 +118,10 @@ fn main() -> Result<(), String> {
 
     for x in xs {
       if let Cirru::List(ys) = x {
+        if ys.first().is_some_and(|y| y == &Cirru::leaf("import")) {
+          import_decls.push(parse_import(&ys)?);
+          continue;
+        }
         let f = parse_function(&ys)?;
         fns.push(f);
       } else {
@@ -100,7 +148,14 @@ fn main() -> Result<(), String> {
   imports.insert(Rc::from("log2"), (log_calx_value, 2));
   imports.insert(Rc::from("log3"), (log_calx_value, 3));
 
-  let mut vm = CalxVM::new(fns, vec![], imports);
+  let mut passes = CalxPassManager::with_level(args.opt_level);
+  passes.print_after = args.print_after;
+  passes.run_syntax(&mut fns);
+
+  let engine = if args.register { CalxEngine::Register } else { CalxEngine::Stack };
+  let mut vm = CalxVM::new_with_engine(fns, vec![], imports, engine);
+  vm.timeout = args.timeout.map(Duration::from_millis);
+  vm.import_decls = import_decls;
 
   // if show_code {
   //   for func in vm.funcs.to_owned() {
@@ -112,6 +167,13 @@ fn main() -> Result<(), String> {
 
   println!("[calx] start preprocessing");
   vm.preprocess(args.verbose)?;
+  passes.run_instrs(&mut vm.funcs);
+
+  if args.tail_calls {
+    for miss in convert_tail_calls(&mut vm.funcs) {
+      println!("[calx] tail call missed: {miss}");
+    }
+  }
 
   vm.setup_top_frame()?;
 
@@ -122,14 +184,30 @@ fn main() -> Result<(), String> {
   }
 
   println!("[calx] start running");
-  match vm.run(vec![Calx::I64(1)]) {
-    Ok(ret) => {
+  let outcome = match args.fuel {
+    Some(fuel) => match vm.run_with_fuel(vec![Calx::I64(1)], fuel) {
+      Ok(CalxFuelOutcome::Finished(ret)) => CalxRunOutcome::Returned(ret),
+      Ok(CalxFuelOutcome::Quit(code)) => CalxRunOutcome::Quit(code),
+      Ok(CalxFuelOutcome::OutOfFuel) => {
+        let elapsed = now.elapsed();
+
+        println!("[calx] out of fuel after {elapsed:.3?}");
+        return Err(String::from("Out of fuel."));
+      }
+      Err(e) => CalxRunOutcome::Trap(e.kind, e),
+    },
+    None => vm.run(vec![Calx::I64(1)]),
+  };
+  match outcome {
+    CalxRunOutcome::Returned(ret) => {
       let elapsed = now.elapsed();
 
       println!("[calx] took {elapsed:.3?}: {ret:?}");
       Ok(())
     }
-    Err(e) => {
+    // exit code is decided by program
+    CalxRunOutcome::Quit(code) => std::process::exit(code as i32),
+    CalxRunOutcome::Trap(_, e) => {
       println!("VM state: {:?}", vm.stack);
       println!("{e}");
       Err(String::from("Failed to run."))
@@ -1,14 +1,17 @@
+mod external;
 mod types;
 
-// use bincode::{Decode, Encode};
+use crate::rc::Rc;
+use bincode::{Decode, Encode};
 use core::fmt;
 use regex::Regex;
-use std::{rc::Rc, str::FromStr, sync::LazyLock};
+use std::{str::FromStr, sync::LazyLock};
 
+pub use external::CalxExtern;
 pub use types::CalxType;
 
 /// Simplied from Calcit, but trying to be basic and mutable
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq, PartialOrd, Decode, Encode)]
 pub enum Calx {
   /// TODO
   Nil,
@@ -22,6 +25,8 @@ pub enum Calx {
   Str(Rc<str>),
   /// TODO
   List(Vec<Calx>),
+  /// host value returned from imports, opaque to instructions
+  Extern(CalxExtern),
   // to simultate linked structures
   // Link(Box<Calx>, Box<Calx>, Box<Calx>),
 }
@@ -67,10 +72,24 @@ impl Calx {
       Calx::F64(_) => t == CalxType::F64,
       Calx::Str(_) => t == CalxType::Str,
       Calx::List(_) => t == CalxType::List,
+      Calx::Extern(x) => match t {
+        CalxType::Extern(None) => true,
+        CalxType::Extern(Some(tag)) => tag == x.tag,
+        _ => false,
+      },
       // Calx::Link(_, _, _) => t == CalxType::Link,
     }
   }
 
+  /// approximate bytes held on heap by strings and lists, not counting the value itself
+  pub fn heap_size(&self) -> usize {
+    match self {
+      Calx::Str(s) => s.len(),
+      Calx::List(xs) => xs.capacity() * std::mem::size_of::<Calx>() + xs.iter().map(Calx::heap_size).sum::<usize>(),
+      _ => 0,
+    }
+  }
+
   pub fn truthy(&self) -> bool {
     match self {
       Calx::Nil => false,
@@ -79,6 +98,7 @@ impl Calx {
       Calx::F64(n) => *n != 0.0,
       Calx::Str(_) => false,
       Calx::List(_) => false,
+      Calx::Extern(_) => false,
       // Calx::Link(_, _, _) => true,
     }
   }
@@ -105,7 +125,9 @@ impl fmt::Display for Calx {
         }
         f.write_str(")")?;
         Ok(())
-      } // Calx::Link(..) => f.write_str("TODO LINK"),
+      }
+      Calx::Extern(x) => write!(f, "{x}"),
+      // Calx::Link(..) => f.write_str("TODO LINK"),
     }
   }
 }
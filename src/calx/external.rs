@@ -0,0 +1,95 @@
+use bincode::{de::Decoder, enc::Encoder, error::DecodeError, error::EncodeError, Decode, Encode};
+use core::fmt;
+use std::any::Any;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::rc::{MaybeSend, Rc};
+
+/// ids of host values, unique within a process
+static NEXT_EXTERN_ID: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(not(feature = "sync"))]
+type CalxExternValue = Rc<dyn Any>;
+
+#[cfg(feature = "sync")]
+type CalxExternValue = Rc<dyn Any + Send + Sync>;
+
+/// host value like a file handle or a parsed document, passed around by Calx code and back to imports.
+/// values are shared by clones, and compared by identity
+#[derive(Clone)]
+pub struct CalxExtern {
+  /// names kind of the value, checked by `CalxType::Extern`
+  pub tag: Rc<str>,
+  /// identity kept by clones and snapshots
+  id: u64,
+  /// `None` after restoring from a snapshot, since host values are not encoded
+  value: Option<CalxExternValue>,
+}
+
+impl CalxExtern {
+  pub fn new<T: Any + MaybeSend>(tag: &str, value: T) -> Self {
+    CalxExtern {
+      tag: Rc::from(tag),
+      id: NEXT_EXTERN_ID.fetch_add(1, Ordering::Relaxed),
+      value: Some(Rc::new(value)),
+    }
+  }
+
+  /// `None` when holding another type, or detached by restoring from a snapshot
+  pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+    self.value.as_ref()?.downcast_ref::<T>()
+  }
+
+  pub fn is_detached(&self) -> bool {
+    self.value.is_none()
+  }
+}
+
+impl PartialEq for CalxExtern {
+  fn eq(&self, other: &Self) -> bool {
+    self.id == other.id && self.tag == other.tag
+  }
+}
+
+/// host values have no order, only same values are comparable
+impl PartialOrd for CalxExtern {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    if self == other {
+      Some(std::cmp::Ordering::Equal)
+    } else {
+      None
+    }
+  }
+}
+
+impl fmt::Debug for CalxExtern {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "CalxExtern({})", self.tag)
+  }
+}
+
+impl fmt::Display for CalxExtern {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "<extern {}>", self.tag)
+  }
+}
+
+/// only the tag and id are encoded, restored values stay equal to each other but hold no host value
+impl Encode for CalxExtern {
+  fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+    self.tag.encode(encoder)?;
+    self.id.encode(encoder)
+  }
+}
+
+impl<Context> Decode<Context> for CalxExtern {
+  fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+    Ok(CalxExtern {
+      tag: Rc::<str>::decode(decoder)?,
+      id: u64::decode(decoder)?,
+      value: None,
+    })
+  }
+}
+
+bincode::impl_borrow_decode!(CalxExtern);
@@ -1,6 +1,8 @@
 use bincode::{Decode, Encode};
 use std::str::FromStr;
 
+use crate::rc::Rc;
+
 /// syntax like `(i64 -> i64)` can be used to types of functions and blocks
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Decode, Encode)]
 pub enum CalxType {
@@ -18,6 +20,8 @@ pub enum CalxType {
   List,
   /// TODO
   Link,
+  /// host value, `extern` for any, `extern.<tag>` for values tagged so
+  Extern(Option<Rc<str>>),
 }
 
 impl FromStr for CalxType {
@@ -32,7 +36,11 @@ impl FromStr for CalxType {
       "str" => Ok(CalxType::Str),
       "list" => Ok(CalxType::List),
       "link" => Ok(CalxType::Link),
-      _ => Err(format!("unknown type: {s}")),
+      "extern" => Ok(CalxType::Extern(None)),
+      _ => match s.strip_prefix("extern.") {
+        Some(tag) if !tag.is_empty() => Ok(CalxType::Extern(Some(Rc::from(tag)))),
+        _ => Err(format!("unknown type: {s}")),
+      },
     }
   }
 }
@@ -1,14 +1,30 @@
 //! Calx VM is a toy VM for learning WebAssembly.
 //! It is a stack machine, and it is dynamically typed. Being an experiment, for Calcit project.
 
+// `CalxError` carries VM state for debugging, so it is large by design
+#![allow(clippy::result_large_err)]
+
 mod calx;
+mod optimize;
 mod parser;
+pub mod rc;
 mod syntax;
 mod util;
 mod vm;
 
-pub use calx::{Calx, CalxType};
-pub use parser::{extract_nested, parse_function};
+pub use calx::{Calx, CalxExtern, CalxType};
+pub use optimize::{
+  compact_instrs, convert_tail_calls, fold_constants, fuse_instrs, inline_funcs, thread_jumps, CalxFuncPass, CalxPass, CalxPassManager,
+  CalxPassStage, CalxTailCallMiss, CalxTailCallMissKind, CALX_INLINE_SIZE,
+};
+pub use parser::{extract_nested, parse_function, parse_import};
 pub use syntax::CalxSyntax;
 pub use util::log_calx_value;
-pub use vm::{func::CalxFunc, instr::CalxInstr, instr::CALX_INSTR_EDITION, CalxImportsDict, CalxVM};
+pub use vm::verifier::{verify_func, verify_funcs, CalxVerifyError, CalxVerifyErrorKind};
+pub use vm::{
+  fuel::CalxFuelCosts, fuel::CalxFuelOutcome, func::CalxFunc, func::CalxInlineHint, import::CalxImport, import::CalxImportClosure,
+  import::CalxImportCtx, import::CalxImportDecl, import::CalxImportFn, import::CalxImportSig, instr::CalxInstr, instr::CalxIntCmp,
+  instr::CALX_INSTR_EDITION, interrupt::CalxInterruptHandle, interrupt::CALX_CHECK_INTERVAL, memory::CalxMemoryUsage,
+  program::CalxProgram, register::CalxEngine, CalxError, CalxErrorKind, CalxImportsDict, CalxRunOutcome, CalxVM, CALX_MAX_CALL_DEPTH,
+  CALX_MAX_STACK_LEN,
+};
@@ -0,0 +1,85 @@
+/*! Optional optimizations, which run between parsing and `preprocess`, or after `preprocess`
+ *
+ */
+
+mod compact;
+mod const_fold;
+mod inline;
+mod jump_thread;
+mod passes;
+mod peephole;
+mod tail_call;
+
+use crate::rc::Rc;
+
+use crate::vm::{func::CalxFunc, instr::CalxInstr};
+
+pub use compact::compact_instrs;
+pub use const_fold::fold_constants;
+pub use inline::{inline_funcs, CALX_INLINE_SIZE};
+pub use jump_thread::thread_jumps;
+pub use passes::{CalxFuncPass, CalxPass, CalxPassManager, CalxPassStage};
+pub use peephole::fuse_instrs;
+pub use tail_call::{convert_tail_calls, CalxTailCallMiss, CalxTailCallMissKind};
+
+/// marks positions reached by jumps, the extra last item is function end
+pub(crate) fn jump_targets(instrs: &[CalxInstr]) -> Vec<bool> {
+  let mut targets = vec![false; instrs.len() + 1];
+  for (pointer, instr) in instrs.iter().enumerate() {
+    if let Some(target) = instr.jump_target(pointer) {
+      if target >= 0 && target as usize <= instrs.len() {
+        targets[target as usize] = true;
+      }
+    }
+  }
+  targets
+}
+
+/// follows `Nop`s and unconditional jumps from `target`, stops at cycles
+pub(crate) fn final_target(instrs: &[CalxInstr], mut target: usize) -> usize {
+  for _ in 0..=instrs.len() {
+    match instrs.get(target) {
+      Some(CalxInstr::Nop) => target += 1,
+      Some(instr) if instr.is_unconditional_jump() => match instr.jump_target(target) {
+        Some(next) if next >= 0 && next as usize <= instrs.len() => target = next as usize,
+        _ => break,
+      },
+      _ => break,
+    }
+  }
+  target
+}
+
+/// removes instructions that are not kept, and rewrites jumps to new positions.
+/// jumping to a removed instruction lands on the next kept one
+pub(crate) fn retain_instrs(f: &mut CalxFunc, keep: &[bool]) {
+  let size = f.instrs.len();
+  let mut positions: Vec<usize> = Vec::with_capacity(size + 1);
+  let mut count = 0;
+  for k in keep {
+    positions.push(count);
+    if *k {
+      count += 1;
+    }
+  }
+  positions.push(count);
+
+  let mut instrs: Vec<CalxInstr> = Vec::with_capacity(count);
+  let mut syntax_map: Vec<usize> = Vec::with_capacity(count);
+  for (pointer, instr) in f.instrs.iter().enumerate() {
+    if !keep[pointer] {
+      continue;
+    }
+    let mut next = instr.to_owned();
+    if let Some(target) = instr.jump_target(pointer) {
+      if target >= 0 && target as usize <= size {
+        next.set_jump_target(positions[pointer], positions[target as usize]);
+      }
+    }
+    instrs.push(next);
+    syntax_map.push(f.syntax_index(pointer));
+  }
+
+  f.instrs = Rc::new(instrs);
+  f.syntax_map = Rc::new(syntax_map);
+}
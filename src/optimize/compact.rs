@@ -0,0 +1,40 @@
+use crate::vm::{func::CalxFunc, instr::CalxInstr};
+
+use super::retain_instrs;
+
+/// drops `Nop`s left by `block`s and instructions not reachable from the function start, runs after `preprocess`.
+/// jumps are rewritten to new positions, and `syntax_map` keeps positions in `syntax` for error messages.
+pub fn compact_instrs(f: &mut CalxFunc) {
+  let instrs = &f.instrs;
+  let size = instrs.len();
+  let mut reachable = vec![false; size];
+  let mut pending: Vec<usize> = vec![0];
+
+  while let Some(pointer) = pending.pop() {
+    if pointer >= size || reachable[pointer] {
+      continue;
+    }
+    reachable[pointer] = true;
+    let instr = &instrs[pointer];
+    match instr {
+      CalxInstr::Return | CalxInstr::ReturnCall(_) | CalxInstr::Quit(_) | CalxInstr::Unreachable => {}
+      a => {
+        if a.jump_target(pointer).is_none() || a.is_conditional_jump() {
+          pending.push(pointer + 1);
+        }
+        if let Some(target) = a.jump_target(pointer) {
+          if target >= 0 {
+            pending.push(target as usize);
+          }
+        }
+      }
+    }
+  }
+
+  let keep: Vec<bool> = instrs
+    .iter()
+    .zip(&reachable)
+    .map(|(instr, r)| *r && !matches!(instr, CalxInstr::Nop))
+    .collect();
+  retain_instrs(f, &keep);
+}
@@ -0,0 +1,129 @@
+use crate::rc::Rc;
+
+use crate::{calx::Calx, syntax::CalxSyntax, vm::func::CalxFunc};
+
+/// folds constant subexpressions in `syntax` into a single `const`, and `if` with constant condition into the taken branch.
+/// operations that would fail at runtime, like overflow or division by zero, are kept so the error still happens at runtime.
+/// runs before `preprocess`, positions of `block` and `if` are updated.
+pub fn fold_constants(f: &mut CalxFunc) {
+  let syntax = &f.syntax;
+  let mut out: Vec<CalxSyntax> = Vec::with_capacity(syntax.len());
+  // new position for each old position, positions inside removed code point to next kept item
+  let mut positions: Vec<usize> = vec![0; syntax.len() + 1];
+  // skipping code in branches not taken, and markers of removed `if`s
+  let mut skip_to: Vec<Option<usize>> = vec![None; syntax.len()];
+  // values before the fence might be reached from jumps, so they are not folded with later instructions
+  let mut fence = 0;
+
+  let mut i = 0;
+  while i < syntax.len() {
+    if let Some(next) = skip_to[i] {
+      for p in &mut positions[i..next] {
+        *p = out.len();
+      }
+      i = next;
+      continue;
+    }
+    positions[i] = out.len();
+
+    match &syntax[i] {
+      CalxSyntax::If { else_at, to, .. } if out.len() > fence && matches!(out.last(), Some(CalxSyntax::Const(_))) => {
+        let cond = match out.pop() {
+          Some(CalxSyntax::Const(v)) => v,
+          _ => unreachable!("checked constant condition"),
+        };
+        // same condition as `jmp-if`
+        if cond == Calx::Bool(true) || cond == Calx::I64(1) {
+          // else branch and `ElseEnd` are placed before then branch
+          skip_to[i] = Some(*else_at);
+          skip_to[*to - 1] = Some(*to);
+        } else {
+          skip_to[i] = Some(i + 1);
+          skip_to[*else_at - 1] = Some(*to);
+        }
+        // current position has to be handled again with skipping
+        continue;
+      }
+      CalxSyntax::Block { .. }
+      | CalxSyntax::BlockEnd(_)
+      | CalxSyntax::If { .. }
+      | CalxSyntax::ElseEnd
+      | CalxSyntax::ThenEnd
+      | CalxSyntax::Do(_) => {
+        out.push(syntax[i].to_owned());
+        fence = out.len();
+      }
+      op => {
+        let folded = match &out[fence..] {
+          [.., CalxSyntax::Const(a), CalxSyntax::Const(b)] if is_binary(op) => eval_binary(op, a, b).map(|v| (2, v)),
+          [.., CalxSyntax::Const(a)] => eval_unary(op, a).map(|v| (1, v)),
+          _ => None,
+        };
+        match folded {
+          Some((size, v)) => {
+            out.truncate(out.len() - size);
+            out.push(CalxSyntax::Const(v));
+          }
+          None => out.push(op.to_owned()),
+        }
+      }
+    }
+    i += 1;
+  }
+  positions[syntax.len()] = out.len();
+
+  for item in &mut out {
+    match item {
+      CalxSyntax::Block { from, to, .. } => {
+        *from = positions[*from];
+        *to = positions[*to];
+      }
+      CalxSyntax::If { else_at, to, .. } => {
+        *else_at = positions[*else_at];
+        *to = positions[*to];
+      }
+      _ => {}
+    }
+  }
+
+  f.syntax = Rc::new(out);
+}
+
+fn is_binary(op: &CalxSyntax) -> bool {
+  use CalxSyntax::*;
+  matches!(
+    op,
+    IntAdd | IntMul | IntDiv | IntRem | IntShr | IntShl | IntEq | IntNe | IntLt | IntLe | IntGt | IntGe | Add | Mul | Div
+  )
+}
+
+/// evaluate like the VM does, `None` if not foldable or the VM would fail
+fn eval_binary(op: &CalxSyntax, a: &Calx, b: &Calx) -> Option<Calx> {
+  use CalxSyntax::*;
+  match (op, a, b) {
+    (IntAdd | Add, Calx::I64(n1), Calx::I64(n2)) => n1.checked_add(*n2).map(Calx::I64),
+    (IntMul | Mul, Calx::I64(n1), Calx::I64(n2)) => n1.checked_mul(*n2).map(Calx::I64),
+    (IntDiv, Calx::I64(n1), Calx::I64(n2)) => n1.checked_div(*n2).map(Calx::I64),
+    (IntRem, Calx::I64(n1), Calx::I64(n2)) => n1.checked_rem(*n2).map(Calx::I64),
+    (IntShr, Calx::I64(n), Calx::I64(bits)) => n.checked_shr(*bits as u32).map(Calx::I64),
+    (IntShl, Calx::I64(n), Calx::I64(bits)) => n.checked_shl(*bits as u32).map(Calx::I64),
+    (IntEq, Calx::I64(n1), Calx::I64(n2)) => Some(Calx::Bool(n1 == n2)),
+    (IntNe, Calx::I64(n1), Calx::I64(n2)) => Some(Calx::Bool(n1 != n2)),
+    (IntLt, Calx::I64(n1), Calx::I64(n2)) => Some(Calx::Bool(n1 < n2)),
+    (IntLe, Calx::I64(n1), Calx::I64(n2)) => Some(Calx::Bool(n1 <= n2)),
+    (IntGt, Calx::I64(n1), Calx::I64(n2)) => Some(Calx::Bool(n1 > n2)),
+    (IntGe, Calx::I64(n1), Calx::I64(n2)) => Some(Calx::Bool(n1 >= n2)),
+    (Add, Calx::F64(n1), Calx::F64(n2)) => Some(Calx::F64(n1 + n2)),
+    (Mul, Calx::F64(n1), Calx::F64(n2)) => Some(Calx::F64(n1 * n2)),
+    (Div, Calx::F64(n1), Calx::F64(n2)) => Some(Calx::F64(n1 / n2)),
+    _ => None,
+  }
+}
+
+fn eval_unary(op: &CalxSyntax, a: &Calx) -> Option<Calx> {
+  match (op, a) {
+    (CalxSyntax::IntNeg, Calx::I64(n)) => n.checked_neg().map(Calx::I64),
+    (CalxSyntax::Neg, Calx::F64(n)) => Some(Calx::F64(-n)),
+    _ => None,
+  }
+}
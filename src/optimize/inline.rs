@@ -0,0 +1,126 @@
+use crate::rc::Rc;
+
+use crate::vm::{
+  func::{CalxFunc, CalxInlineHint},
+  instr::CalxInstr,
+};
+
+/// functions with more instructions are not inlined, unless marked with `inline`
+pub const CALX_INLINE_SIZE: usize = 24;
+
+/// params size and body of a function that can be inlined
+type InlineBody = Option<(usize, Rc<Vec<CalxInstr>>)>;
+
+/// substitutes bodies of small leaf functions at their call sites, runs after `preprocess`.
+/// callee params are moved into extra locals appended after locals of caller,
+/// and `return` in callee becomes a jump to the end of inlined body.
+pub fn inline_funcs(funcs: &mut [CalxFunc], max_size: usize) {
+  // leaf functions are never changed by inlining, so bodies are taken before
+  let callees: Vec<InlineBody> = funcs
+    .iter()
+    .map(|f| is_inlinable(f, max_size).then(|| (f.params_types.len(), f.instrs.to_owned())))
+    .collect();
+
+  for f in funcs.iter_mut() {
+    // inlined bodies never nest, so callees share the extra locals
+    let extra = f
+      .instrs
+      .iter()
+      .filter_map(|instr| match instr {
+        CalxInstr::Call(idx) => callees.get(*idx)?.as_ref().map(|(size, _)| *size),
+        _ => None,
+      })
+      .max();
+    if let Some(extra) = extra {
+      inline_into(f, &callees, extra);
+    }
+  }
+}
+
+fn inline_into(f: &mut CalxFunc, callees: &[InlineBody], extra: usize) {
+  let base = f.count_locals();
+  let mut instrs: Vec<CalxInstr> = Vec::with_capacity(f.instrs.len());
+  let mut syntax_map: Vec<usize> = Vec::with_capacity(f.instrs.len());
+  let mut positions: Vec<usize> = Vec::with_capacity(f.instrs.len() + 1);
+  // new position and old position of jumps in caller, rewritten after all positions are known
+  let mut jumps: Vec<(usize, usize)> = vec![];
+
+  for (pointer, instr) in f.instrs.iter().enumerate() {
+    positions.push(instrs.len());
+    let syntax_idx = f.syntax_index(pointer);
+    let callee = match instr {
+      CalxInstr::Call(idx) => callees.get(*idx).and_then(|c| c.as_ref()),
+      _ => None,
+    };
+    match callee {
+      Some((size, body)) => {
+        for k in (0..*size).rev() {
+          instrs.push(CalxInstr::LocalSet(base + k));
+        }
+        let start = instrs.len();
+        let end = start + body.len();
+        for (k, x) in body.iter().enumerate() {
+          let mut next = match x {
+            CalxInstr::Return => CalxInstr::Jmp(end),
+            _ => x.to_owned(),
+          };
+          next.map_locals(|i| base + i);
+          if let Some(target) = x.jump_target(k) {
+            next.set_jump_target(start + k, start + target as usize);
+          }
+          instrs.push(next);
+        }
+        syntax_map.resize(instrs.len(), syntax_idx);
+      }
+      None => {
+        if instr.jump_target(pointer).is_some() {
+          jumps.push((instrs.len(), pointer));
+        }
+        instrs.push(instr.to_owned());
+        syntax_map.push(syntax_idx);
+      }
+    }
+  }
+  positions.push(instrs.len());
+
+  for (at, pointer) in jumps {
+    if let Some(target) = f.instrs[pointer].jump_target(pointer) {
+      if target >= 0 && (target as usize) < positions.len() {
+        instrs[at].set_jump_target(at, positions[target as usize]);
+      }
+    }
+  }
+
+  if f.local_names.len() == base {
+    let mut names = (*f.local_names).to_owned();
+    for k in 0..extra {
+      names.push(format!("%inline.{k}"));
+    }
+    f.local_names = Rc::new(names);
+  }
+  f.locals_size = base + extra;
+  f.instrs = Rc::new(instrs);
+  f.syntax_map = Rc::new(syntax_map);
+}
+
+/// leaf functions that only use their params as locals
+fn is_inlinable(f: &CalxFunc, max_size: usize) -> bool {
+  match f.inline_hint {
+    CalxInlineHint::Never => return false,
+    CalxInlineHint::Auto if f.instrs.len() > max_size => return false,
+    _ => {}
+  }
+  let size = f.instrs.len();
+  let params_size = f.params_types.len();
+  f.instrs.iter().enumerate().all(|(pointer, instr)| {
+    let mut valid = !matches!(instr, CalxInstr::Call(_) | CalxInstr::ReturnCall(_) | CalxInstr::LocalNew);
+    if let Some(target) = instr.jump_target(pointer) {
+      valid = valid && target >= 0 && target as usize <= size;
+    }
+    instr.to_owned().map_locals(|i| {
+      valid = valid && i < params_size;
+      i
+    });
+    valid
+  })
+}
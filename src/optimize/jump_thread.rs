@@ -0,0 +1,77 @@
+use crate::rc::Rc;
+
+use crate::vm::{
+  func::CalxFunc,
+  instr::{CalxInstr, CalxIntCmp},
+};
+
+use super::{final_target, jump_targets, retain_instrs};
+
+/// threads chains of jumps to their final targets, removes plain jumps to next instruction,
+/// and inverts comparisons in `cmp; jmp-if +2; jmp B` to skip the extra jump. runs after `preprocess`.
+pub fn thread_jumps(f: &mut CalxFunc) {
+  // removing jumps may expose more jumps to next instruction
+  loop {
+    let mut instrs: Vec<CalxInstr> = (*f.instrs).to_owned();
+    let size = instrs.len();
+
+    for pointer in 0..size {
+      let target = match instrs[pointer].jump_target(pointer) {
+        Some(t) if t >= 0 && t as usize <= size => final_target(&instrs, t as usize),
+        _ => continue,
+      };
+      instrs[pointer].set_jump_target(pointer, target);
+      if instrs[pointer].is_unconditional_jump() && target < size && instrs[target] == CalxInstr::Return {
+        instrs[pointer] = CalxInstr::Return;
+      }
+    }
+
+    let targets = jump_targets(&instrs);
+    let mut keep = vec![true; size];
+    for pointer in 0..size {
+      if !keep[pointer] {
+        continue;
+      }
+      if instrs[pointer].jump_target(pointer) == Some(pointer as i64 + 1) {
+        match instrs[pointer] {
+          // condition is still popped
+          CalxInstr::JmpIf(_) | CalxInstr::JmpOffsetIf(_) => instrs[pointer] = CalxInstr::Drop,
+          CalxInstr::Jmp(_) | CalxInstr::JmpOffset(_) => keep[pointer] = false,
+          // still checks its local, which may fail at runtime
+          _ => {}
+        }
+        continue;
+      }
+      if instrs[pointer].jump_target(pointer) != Some(pointer as i64 + 2) || pointer + 1 >= size || targets[pointer + 1] {
+        continue;
+      }
+      let skip_to = match instrs[pointer + 1].jump_target(pointer + 1) {
+        Some(t) if instrs[pointer + 1].is_unconditional_jump() => t as usize,
+        _ => continue,
+      };
+      let prev_cmp = if pointer > 0 && !targets[pointer] {
+        CalxIntCmp::from_instr(&instrs[pointer - 1])
+      } else {
+        None
+      };
+      match (&mut instrs[pointer], prev_cmp) {
+        (CalxInstr::JmpIfLocalConst { cmp, to, .. }, _) => {
+          *cmp = cmp.inverse();
+          *to = skip_to;
+        }
+        (CalxInstr::JmpIf(_) | CalxInstr::JmpOffsetIf(_), Some(cmp)) => {
+          instrs[pointer].set_jump_target(pointer, skip_to);
+          instrs[pointer - 1] = cmp.inverse().to_instr();
+        }
+        _ => continue,
+      }
+      keep[pointer + 1] = false;
+    }
+
+    f.instrs = Rc::new(instrs);
+    if keep.iter().all(|k| *k) {
+      break;
+    }
+    retain_instrs(f, &keep);
+  }
+}
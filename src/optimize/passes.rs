@@ -0,0 +1,174 @@
+use std::fmt;
+
+use crate::vm::func::CalxFunc;
+
+use super::{compact_instrs, convert_tail_calls, fold_constants, fuse_instrs, inline_funcs, thread_jumps, CALX_INLINE_SIZE};
+
+/// when a pass runs, it decides which IR of `CalxFunc` is changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalxPassStage {
+  /// on `syntax`, before `preprocess`
+  Syntax,
+  /// on `instrs`, after `preprocess`
+  Instrs,
+}
+
+/// an optimization over all functions of a program
+pub trait CalxPass {
+  fn name(&self) -> &str;
+  fn stage(&self) -> CalxPassStage;
+  fn run(&self, funcs: &mut [CalxFunc]);
+}
+
+/// pass that changes functions one by one
+pub struct CalxFuncPass {
+  pub name: String,
+  pub stage: CalxPassStage,
+  pub f: fn(&mut CalxFunc),
+}
+
+impl CalxPass for CalxFuncPass {
+  fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn stage(&self) -> CalxPassStage {
+    self.stage
+  }
+
+  fn run(&self, funcs: &mut [CalxFunc]) {
+    for f in funcs {
+      (self.f)(f);
+    }
+  }
+}
+
+struct InlinePass;
+
+impl CalxPass for InlinePass {
+  fn name(&self) -> &str {
+    "inline"
+  }
+
+  fn stage(&self) -> CalxPassStage {
+    CalxPassStage::Instrs
+  }
+
+  fn run(&self, funcs: &mut [CalxFunc]) {
+    inline_funcs(funcs, CALX_INLINE_SIZE);
+  }
+}
+
+struct TailCallPass;
+
+impl CalxPass for TailCallPass {
+  fn name(&self) -> &str {
+    "tail-calls"
+  }
+
+  fn stage(&self) -> CalxPassStage {
+    CalxPassStage::Instrs
+  }
+
+  fn run(&self, funcs: &mut [CalxFunc]) {
+    // calls not converted are only reported by `convert_tail_calls`
+    let _ = convert_tail_calls(funcs);
+  }
+}
+
+/// runs passes in the order they are added, and prints functions after passes listed in `print_after`
+#[derive(Default)]
+pub struct CalxPassManager {
+  passes: Vec<Box<dyn CalxPass>>,
+  /// names of passes, or `all`
+  pub print_after: Vec<String>,
+}
+
+impl fmt::Debug for CalxPassManager {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_list().entries(self.passes.iter().map(|p| p.name())).finish()
+  }
+}
+
+impl CalxPassManager {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// preset passes for `-O0` to `-O3`, higher levels are treated as `-O3`
+  pub fn with_level(level: u8) -> Self {
+    let mut pm = Self::new();
+    if level >= 1 {
+      pm.add(CalxFuncPass {
+        name: "fold-constants".to_owned(),
+        stage: CalxPassStage::Syntax,
+        f: fold_constants,
+      });
+    }
+    if level >= 3 {
+      pm.add(InlinePass);
+    }
+    if level >= 2 {
+      pm.add(TailCallPass);
+      pm.add(CalxFuncPass {
+        name: "thread-jumps".to_owned(),
+        stage: CalxPassStage::Instrs,
+        f: thread_jumps,
+      });
+    }
+    if level >= 1 {
+      pm.add(CalxFuncPass {
+        name: "compact".to_owned(),
+        stage: CalxPassStage::Instrs,
+        f: compact_instrs,
+      });
+    }
+    if level >= 2 {
+      pm.add(CalxFuncPass {
+        name: "fuse".to_owned(),
+        stage: CalxPassStage::Instrs,
+        f: fuse_instrs,
+      });
+    }
+    pm
+  }
+
+  pub fn add(&mut self, pass: impl CalxPass + 'static) {
+    self.passes.push(Box::new(pass));
+  }
+
+  /// names of passes in order
+  pub fn names(&self) -> Vec<&str> {
+    self.passes.iter().map(|p| p.name()).collect()
+  }
+
+  /// runs passes of `Syntax` stage, before `preprocess`
+  pub fn run_syntax(&self, funcs: &mut [CalxFunc]) {
+    self.run_stage(CalxPassStage::Syntax, funcs)
+  }
+
+  /// runs passes of `Instrs` stage, after `preprocess`
+  pub fn run_instrs(&self, funcs: &mut [CalxFunc]) {
+    self.run_stage(CalxPassStage::Instrs, funcs)
+  }
+
+  fn run_stage(&self, stage: CalxPassStage, funcs: &mut [CalxFunc]) {
+    for pass in self.passes.iter().filter(|p| p.stage() == stage) {
+      pass.run(funcs);
+      if self.print_after.iter().any(|n| n == "all" || n == pass.name()) {
+        println!("[calx] after pass {}", pass.name());
+        for f in funcs.iter() {
+          match stage {
+            CalxPassStage::Syntax => {
+              println!("fn {}", f.name);
+              for (idx, x) in f.syntax.iter().enumerate() {
+                println!("  {idx:02} {x:?}");
+              }
+            }
+            CalxPassStage::Instrs => println!("{f}"),
+          }
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,56 @@
+use crate::rc::Rc;
+
+use crate::{
+  calx::Calx,
+  vm::{
+    func::CalxFunc,
+    instr::{CalxInstr, CalxIntCmp},
+  },
+};
+
+use super::{jump_targets, retain_instrs};
+
+/// replaces common sequences in `instrs` with fused instructions, runs after `preprocess`.
+/// a sequence is not fused when jumps land inside it.
+pub fn fuse_instrs(f: &mut CalxFunc) {
+  let targets = jump_targets(&f.instrs);
+  let mut instrs: Vec<CalxInstr> = (*f.instrs).to_owned();
+  let mut keep = vec![true; instrs.len()];
+
+  let mut i = 0;
+  while i < instrs.len() {
+    match match_sequence(&instrs[i..]) {
+      Some((size, fused)) if !targets[i + 1..i + size].contains(&true) => {
+        instrs[i] = fused;
+        for k in &mut keep[i + 1..i + size] {
+          *k = false;
+        }
+        i += size;
+      }
+      _ => i += 1,
+    }
+  }
+
+  f.instrs = Rc::new(instrs);
+  retain_instrs(f, &keep);
+}
+
+/// size of matched sequence, and the instruction to replace it
+fn match_sequence(xs: &[CalxInstr]) -> Option<(usize, CalxInstr)> {
+  use CalxInstr::*;
+  match xs {
+    [LocalGet(a), Const(Calx::I64(k)), IntAdd, LocalSet(b), ..] if a == b => Some((4, LocalIncrease(*a, *k))),
+    [LocalGet(a), Const(Calx::I64(k)), cmp, JmpIf(to), ..] if CalxIntCmp::from_instr(cmp).is_some() => Some((
+      4,
+      JmpIfLocalConst {
+        local: *a,
+        cmp: CalxIntCmp::from_instr(cmp)?,
+        value: *k,
+        to: *to,
+      },
+    )),
+    [LocalGet(a), LocalGet(b), IntAdd, ..] => Some((3, IntAddLocals(*a, *b))),
+    [LocalGet(a), Const(Calx::I64(k)), IntAdd, ..] => Some((3, IntAddLocalConst(*a, *k))),
+    _ => None,
+  }
+}
@@ -0,0 +1,127 @@
+use crate::rc::Rc;
+use std::fmt;
+
+use crate::vm::{func::CalxFunc, instr::CalxInstr};
+
+use super::final_target;
+
+/// recursive call that is kept as `Call`, reported by `convert_tail_calls`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalxTailCallMiss {
+  pub func: Rc<str>,
+  pub pointer: usize,
+  pub callee: Rc<str>,
+  pub kind: CalxTailCallMissKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CalxTailCallMissKind {
+  /// more instructions run after the call returns
+  NotInTailPosition,
+  /// callee returns different number of values
+  ReturnSize { expected: usize, found: usize },
+}
+
+impl fmt::Display for CalxTailCallMissKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::NotInTailPosition => f.write_str("not in tail position"),
+      Self::ReturnSize { expected, found } => write!(f, "return size mismatch, expected {expected}, found {found}"),
+    }
+  }
+}
+
+impl fmt::Display for CalxTailCallMiss {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{}@{}: recursive call to {}, {}",
+      self.func, self.pointer, self.callee, self.kind
+    )
+  }
+}
+
+/// rewrites `call f` followed by `return`, or at function end, into `return-call f`, runs after `preprocess`.
+/// functions are expected to pass `verify`, so a call in tail position with same return size
+/// leaves only params of callee on stack. returns recursive calls that are not converted.
+pub fn convert_tail_calls(funcs: &mut [CalxFunc]) -> Vec<CalxTailCallMiss> {
+  let recursive = recursive_calls(funcs);
+  let mut misses: Vec<CalxTailCallMiss> = vec![];
+
+  for idx in 0..funcs.len() {
+    let mut instrs: Vec<CalxInstr> = (*funcs[idx].instrs).to_owned();
+    let ret_size = funcs[idx].ret_types.len();
+    let mut changed = false;
+
+    for pointer in 0..instrs.len() {
+      let callee = match instrs[pointer] {
+        CalxInstr::Call(callee) if callee < funcs.len() => callee,
+        _ => continue,
+      };
+      let callee_ret_size = funcs[callee].ret_types.len();
+      let next = final_target(&instrs, pointer + 1);
+      let kind = if next < instrs.len() && instrs[next] != CalxInstr::Return {
+        CalxTailCallMissKind::NotInTailPosition
+      } else if callee_ret_size != ret_size {
+        CalxTailCallMissKind::ReturnSize {
+          expected: ret_size,
+          found: callee_ret_size,
+        }
+      } else {
+        instrs[pointer] = CalxInstr::ReturnCall(callee);
+        changed = true;
+        continue;
+      };
+      if recursive[idx][callee] {
+        misses.push(CalxTailCallMiss {
+          func: funcs[idx].name.to_owned(),
+          pointer,
+          callee: funcs[callee].name.to_owned(),
+          kind,
+        });
+      }
+    }
+
+    if changed {
+      funcs[idx].instrs = Rc::new(instrs);
+    }
+  }
+
+  misses
+}
+
+/// `[caller][callee]` is true when callee might call back into caller
+fn recursive_calls(funcs: &[CalxFunc]) -> Vec<Vec<bool>> {
+  let size = funcs.len();
+  let callees: Vec<Vec<usize>> = funcs
+    .iter()
+    .map(|f| {
+      f.instrs
+        .iter()
+        .filter_map(|instr| match instr {
+          CalxInstr::Call(i) | CalxInstr::ReturnCall(i) if *i < size => Some(*i),
+          _ => None,
+        })
+        .collect()
+    })
+    .collect();
+
+  // functions reachable from each function through calls
+  let reachable: Vec<Vec<bool>> = (0..size)
+    .map(|start| {
+      let mut seen = vec![false; size];
+      let mut pending: Vec<usize> = callees[start].to_owned();
+      while let Some(i) = pending.pop() {
+        if !seen[i] {
+          seen[i] = true;
+          pending.extend(&callees[i]);
+        }
+      }
+      seen
+    })
+    .collect();
+
+  (0..size)
+    .map(|caller| (0..size).map(|callee| reachable[callee][caller]).collect())
+    .collect()
+}
@@ -4,13 +4,14 @@
 
 mod locals;
 
-use std::rc::Rc;
+use crate::rc::Rc;
 
 use cirru_parser::Cirru;
 
 use crate::calx::CalxType;
 use crate::syntax::CalxSyntax;
-use crate::vm::func::CalxFunc;
+use crate::vm::func::{CalxFunc, CalxInlineHint};
+use crate::vm::import::{CalxImportDecl, CalxImportSig};
 
 use self::locals::LocalsCollector;
 
@@ -40,9 +41,18 @@ pub fn parse_function(nodes: &[Cirru]) -> Result<CalxFunc, String> {
 
   let (params_types, ret_types) = parse_fn_types(&nodes[2], &mut locals_collector)?;
 
+  let mut inline_hint = CalxInlineHint::Auto;
   let mut ptr_base: usize = 0;
   for (idx, line) in nodes.iter().enumerate() {
     if idx >= 3 {
+      if let Cirru::Leaf(attr) = line {
+        inline_hint = match &**attr {
+          "inline" => CalxInlineHint::Always,
+          "noinline" => CalxInlineHint::Never,
+          _ => return Err(format!("unknown function attribute: {attr}")),
+        };
+        continue;
+      }
       for expanded in extract_nested(line)? {
         // println!("expanded {}", expanded);
         let syntax = parse_instr(ptr_base, &expanded, &mut locals_collector)?;
@@ -59,9 +69,31 @@ pub fn parse_function(nodes: &[Cirru]) -> Result<CalxFunc, String> {
     name,
     params_types: params_types.into(),
     ret_types: Rc::new(ret_types),
+    locals_size: locals_collector.locals.len(),
     local_names: Rc::new(locals_collector.locals),
     syntax: Rc::new(body),
     instrs: Rc::new(vec![]),
+    syntax_map: Rc::new(vec![]),
+    inline_hint,
+  })
+}
+
+/// parses
+/// ```cirru
+/// import <f-name> (i64 i64 -> i64)
+/// ```
+pub fn parse_import(nodes: &[Cirru]) -> Result<CalxImportDecl, String> {
+  if nodes.len() != 3 || !leaf_is(&nodes[0], "import") {
+    return Err(format!("import expects a name and types, got {nodes:?}"));
+  }
+  let name: Rc<str> = match &nodes[1] {
+    Cirru::Leaf(x) => (**x).into(),
+    Cirru::List(_) => return Err(format!("invalid import name, {}", nodes[1])),
+  };
+  let (params, rets) = parse_block_types(&nodes[2])?;
+  Ok(CalxImportDecl {
+    name,
+    sig: CalxImportSig { params, rets },
   })
 }
 
@@ -326,8 +358,13 @@ pub fn parse_if(ptr_base: usize, xs: &[Cirru], collector: &mut LocalsCollector)
   }
   let types = parse_block_types(&xs[1])?;
   let ret_types = types.1;
-  let then_syntax = parse_do(&xs[2], collector)?;
-  let else_syntax = if xs.len() == 4 { parse_do(&xs[3], collector)? } else { vec![] };
+  // else branch is placed first, its size decides positions inside then branch
+  let else_syntax = if xs.len() == 4 {
+    parse_do(ptr_base + 1, &xs[3], collector)?
+  } else {
+    vec![]
+  };
+  let then_syntax = parse_do(ptr_base + else_syntax.len() + 2, &xs[2], collector)?;
 
   let mut p = ptr_base + 1; // leave a place for if instruction
   let mut chunk: Vec<CalxSyntax> = vec![];
@@ -362,7 +399,8 @@ pub fn parse_if(ptr_base: usize, xs: &[Cirru], collector: &mut LocalsCollector)
   Ok(chunk)
 }
 
-pub fn parse_do(xs: &Cirru, collector: &mut LocalsCollector) -> Result<Vec<CalxSyntax>, String> {
+/// `ptr_base` is the position of first instruction inside `do`
+pub fn parse_do(ptr_base: usize, xs: &Cirru, collector: &mut LocalsCollector) -> Result<Vec<CalxSyntax>, String> {
   match xs {
     Cirru::Leaf(_) => Err(format!("expect expression for types, got {xs}")),
     Cirru::List(ys) => {
@@ -371,13 +409,15 @@ pub fn parse_do(xs: &Cirru, collector: &mut LocalsCollector) -> Result<Vec<CalxS
         return Err(format!("expected do, got {x0}"));
       }
 
+      let mut p = ptr_base;
       let mut chunk: Vec<CalxSyntax> = vec![];
       for (idx, x) in ys.iter().enumerate() {
         if idx > 0 {
           let lines = extract_nested(x)?;
           for expanded in &lines {
-            let instrs = parse_instr(idx, expanded, collector)?;
+            let instrs = parse_instr(p, expanded, collector)?;
             for y in instrs {
+              p += 1;
               chunk.push(y);
             }
           }
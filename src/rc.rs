@@ -0,0 +1,50 @@
+//! Pointer for values shared among functions, frames and stacks.
+//! It's `std::rc::Rc` by default, and `std::sync::Arc` with feature `sync`,
+//! so that values, functions and VMs can be sent to other threads.
+
+#[cfg(not(feature = "sync"))]
+pub use std::rc::Rc;
+
+#[cfg(feature = "sync")]
+pub use std::sync::Arc as Rc;
+
+/// shared and mutable, `RefCell` by default and `Mutex` with feature `sync`
+#[cfg(not(feature = "sync"))]
+pub type RcCell<T> = Rc<std::cell::RefCell<T>>;
+
+#[cfg(feature = "sync")]
+pub type RcCell<T> = Rc<std::sync::Mutex<T>>;
+
+#[cfg(not(feature = "sync"))]
+pub(crate) fn rc_cell<T>(x: T) -> RcCell<T> {
+  Rc::new(std::cell::RefCell::new(x))
+}
+
+#[cfg(feature = "sync")]
+pub(crate) fn rc_cell<T>(x: T) -> RcCell<T> {
+  Rc::new(std::sync::Mutex::new(x))
+}
+
+#[cfg(not(feature = "sync"))]
+pub(crate) fn borrow_cell<T: ?Sized>(x: &RcCell<T>) -> std::cell::RefMut<'_, T> {
+  x.borrow_mut()
+}
+
+/// a panic in another thread does not leave the value broken for VMs, so poisoning is ignored
+#[cfg(feature = "sync")]
+pub(crate) fn borrow_cell<T: ?Sized>(x: &RcCell<T>) -> std::sync::MutexGuard<'_, T> {
+  x.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// bound for host values kept by VM, nothing by default, and `Send + Sync` with feature `sync`
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSend {}
+
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSend for T {}
+
+#[cfg(feature = "sync")]
+pub trait MaybeSend: Send + Sync {}
+
+#[cfg(feature = "sync")]
+impl<T: ?Sized + Send + Sync> MaybeSend for T {}
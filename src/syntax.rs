@@ -1,9 +1,10 @@
-use std::rc::Rc;
+use crate::rc::Rc;
+use bincode::{Decode, Encode};
 
 use crate::{Calx, CalxType};
 
 /// learning from WASM but for dynamic data
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq, PartialOrd, Decode, Encode)]
 pub enum CalxSyntax {
   /// `local.set`, pop from stack, set value at position
   LocalSet(usize),
@@ -11,7 +12,7 @@ pub enum CalxSyntax {
   LocalTee(usize),
   /// `local.get`, get value at position load on stack
   LocalGet(usize),
-  /// `local.new`, increase size of array of locals
+  /// `local.new`, deprecated since locals are created when function is called, compiled to `Nop`
   LocalNew,
   /// `global.set`, set global value at position
   GlobalSet(usize),
@@ -1,11 +1,19 @@
 mod block_data;
 pub mod frame;
+pub mod fuel;
 pub mod func;
+pub mod import;
 pub mod instr;
-
+pub mod interrupt;
+pub mod memory;
+pub mod program;
+pub mod register;
+mod snapshot;
+pub mod verifier;
+
+use crate::rc::Rc;
 use std::collections::hash_map::HashMap;
-use std::ops::Rem;
-use std::rc::Rc;
+use std::time::Duration;
 use std::{fmt, mem, vec};
 
 use crate::calx::Calx;
@@ -14,8 +22,18 @@ use crate::vm::block_data::BlockStack;
 
 use self::block_data::BlockData;
 use self::frame::CalxFrame;
+use self::fuel::{CalxFuelCosts, CalxFuelOutcome};
 use self::func::CalxFunc;
+use self::import::{find_import, import_table, CalxImport, CalxImportClosures, CalxImportDecl, CalxImportSig, CalxUserData};
 use self::instr::CalxInstr;
+use self::interrupt::{CalxInterruptCheck, CalxInterruptHandle, CALX_CHECK_INTERVAL};
+use self::register::{CalxEngine, CalxRegCache};
+use self::verifier::CalxVerifyError;
+
+/// default of `max_call_depth`
+pub const CALX_MAX_CALL_DEPTH: usize = 10_000;
+/// default of `max_stack_len`
+pub const CALX_MAX_STACK_LEN: usize = 1 << 20;
 
 pub type CalxImportsDict = HashMap<Rc<str>, (fn(xs: &Vec<Calx>) -> Result<Calx, CalxError>, usize)>;
 
@@ -34,9 +52,49 @@ pub struct CalxVM {
   pub frames: Vec<CalxFrame>,
   pub top_frame: CalxFrame,
   pub imports: CalxImportsDict,
+  /// imports resolved from `imports` by `preprocess`, indexed by `CallImport`
+  pub import_table: Vec<CalxImport>,
   /// extra status to tracking runnnig finished
   pub finished: bool,
   pub return_value: Calx,
+  pub engine: CalxEngine,
+  /// fuel left for `run_with_fuel` and `resume`
+  pub fuel: u64,
+  pub fuel_costs: CalxFuelCosts,
+  /// stops running with `CalxErrorKind::Interrupted` when triggered, get a shared one with `interrupt_handle`
+  pub interrupt: CalxInterruptHandle,
+  /// stops running with `CalxErrorKind::Timeout` after this long, counted from the start of each run
+  pub timeout: Option<Duration>,
+  /// instructions run between checks of `interrupt` and `timeout`
+  pub check_interval: u32,
+  /// frames including the one of `main`, checked at `call`
+  pub max_call_depth: usize,
+  /// values on stack of all frames, checked at calls and jumps.
+  /// register engine counts slots of all frames instead
+  pub max_stack_len: usize,
+  /// limit of heap bytes held by strings and lists, see `memory_usage`
+  /// usage close to the limit is checked in intervals, so it may pass by up to 1/16 before reported
+  pub max_memory: Option<usize>,
+  /// bytes of values created since usage was last measured, added to last measured usage
+  heap_estimate: usize,
+  /// set by `quit`, running finishes with `CalxRunOutcome::Quit`
+  pub quit_code: Option<usize>,
+  /// globals given to `new`, restored by `reset_globals`
+  initial_globals: Vec<Calx>,
+  /// added by `add_import`, merged with `imports` into `import_table`
+  import_closures: CalxImportClosures,
+  /// set by `set_user_data`, for imported closures
+  user_data: CalxUserData,
+  /// signatures registered by host with `add_typed_import` or `set_import_sig`
+  import_sigs: HashMap<Rc<str>, CalxImportSig>,
+  /// from `import` in source, checked against imports registered by host in `preprocess`
+  pub import_decls: Vec<CalxImportDecl>,
+  /// closures being called, which can not be entered again with `CalxImportCtx::call`
+  active_imports: Vec<usize>,
+  /// depth of calls from imports with `CalxImportCtx::call`
+  nested_calls: usize,
+  /// functions lowered by register engine, cleared by `preprocess` and when imports change
+  reg_cache: Option<CalxRegCache>,
 }
 
 impl std::fmt::Debug for CalxVM {
@@ -47,28 +105,65 @@ impl std::fmt::Debug for CalxVM {
 
 impl CalxVM {
   pub fn new(fns: Vec<CalxFunc>, globals: Vec<Calx>, imports: CalxImportsDict) -> Self {
-    let main_func = fns.iter().find(|x| &*x.name == "main").expect("main function is required");
-    let main_frame = CalxFrame {
-      name: main_func.name.clone(),
-      initial_stack_size: 0,
-      // use empty instrs, will be replaced by preprocess
-      instrs: Rc::new(vec![]),
-      pointer: 0,
-      locals: vec![],
-      ret_types: main_func.ret_types.clone(),
+    Self::new_with_engine(fns, globals, imports, CalxEngine::Stack)
+  }
+
+  /// like `new`, with a choice of engine for running instructions
+  pub fn new_with_engine(fns: Vec<CalxFunc>, globals: Vec<Calx>, imports: CalxImportsDict, engine: CalxEngine) -> Self {
+    // `main` is optional, libraries of functions are entered with `call`
+    let main_frame = match fns.iter().find(|x| &*x.name == "main") {
+      Some(main_func) => CalxFrame {
+        name: main_func.name.clone(),
+        initial_stack_size: 0,
+        // use empty instrs, will be replaced by preprocess
+        instrs: Rc::new(vec![]),
+        pointer: 0,
+        locals: vec![],
+        ret_types: main_func.ret_types.clone(),
+      },
+      None => CalxFrame::default(),
     };
     CalxVM {
       stack: vec![],
+      initial_globals: globals.to_owned(),
+      import_closures: HashMap::new(),
+      user_data: CalxUserData::default(),
+      import_sigs: HashMap::new(),
+      import_decls: vec![],
+      active_imports: vec![],
+      nested_calls: 0,
+      reg_cache: None,
       globals,
       funcs: fns,
       frames: vec![],
       top_frame: main_frame,
+      import_table: import_table(&imports, &HashMap::new()),
       imports,
       return_value: Calx::Nil,
       finished: false,
+      engine,
+      fuel: 0,
+      fuel_costs: CalxFuelCosts::default(),
+      interrupt: CalxInterruptHandle::new(),
+      timeout: None,
+      check_interval: CALX_CHECK_INTERVAL,
+      max_call_depth: CALX_MAX_CALL_DEPTH,
+      max_stack_len: CALX_MAX_STACK_LEN,
+      max_memory: None,
+      heap_estimate: 0,
+      quit_code: None,
     }
   }
 
+  /// handle sharing the flag of this VM, for stopping it from another thread
+  pub fn interrupt_handle(&self) -> CalxInterruptHandle {
+    self.interrupt.to_owned()
+  }
+
+  pub(crate) fn interrupt_check(&self) -> CalxInterruptCheck {
+    CalxInterruptCheck::new(&self.interrupt, self.timeout, self.check_interval)
+  }
+
   pub fn setup_top_frame(&mut self) -> Result<(), String> {
     self.top_frame.instrs = match self.find_func("main") {
       Some(f) => f.instrs.to_owned(),
@@ -111,16 +206,190 @@ impl CalxVM {
     output
   }
 
-  pub fn run(&mut self, args: Vec<Calx>) -> Result<Calx, CalxError> {
-    // assign function parameters
-    self.top_frame.locals = args;
-    self.stack.clear();
+  /// runs `main`, errors and `quit` are also returned to host as outcomes
+  pub fn run(&mut self, args: Vec<Calx>) -> CalxRunOutcome {
+    let result = match self.find_func_idx("main") {
+      Some((idx, _)) => match self.engine {
+        CalxEngine::Stack => self.run_stack(idx, args),
+        CalxEngine::Register => self.run_register(idx, args),
+      },
+      None => Err(CalxError::new_raw(String::from("main function is required"))),
+    };
+    match (result.map(|xs| xs.last().cloned().unwrap_or(Calx::Nil)), self.quit_code) {
+      (Ok(_), Some(code)) => CalxRunOutcome::Quit(code),
+      (Ok(v), None) => CalxRunOutcome::Returned(v),
+      (Err(e), _) => CalxRunOutcome::Trap(e.kind, e),
+    }
+  }
+
+  /// calls a function by name with a fresh frame, `main` is not required.
+  /// arguments are checked against params, and exactly one value is returned for each of `ret_types`
+  pub fn call(&mut self, name: &str, args: &[Calx]) -> Result<Vec<Calx>, CalxError> {
+    let idx = match self.find_func_idx(name) {
+      Some((idx, _)) => idx,
+      None => return Err(CalxError::new_raw(format!("unknown function {name}"))),
+    };
+    self.check_call_args(idx, args)?;
+
+    let values = match self.engine {
+      CalxEngine::Stack => self.run_stack(idx, args.to_vec())?,
+      CalxEngine::Register => self.run_register(idx, args.to_vec())?,
+    };
+    self.check_call_rets(idx, values)
+  }
+
+  /// arguments should fit params of function at `idx`
+  pub(crate) fn check_call_args(&self, idx: usize, args: &[Calx]) -> Result<(), CalxError> {
+    let f = match self.funcs.get(idx) {
+      Some(f) => f,
+      None => return Err(CalxError::new_raw(format!("unknown function #{idx}"))),
+    };
+    let name = &f.name;
+    if args.len() != f.params_types.len() {
+      return Err(CalxError::new_raw(format!(
+        "function {name} expects {} arguments, got {}",
+        f.params_types.len(),
+        args.len()
+      )));
+    }
+    for (i, (t, v)) in f.params_types.iter().zip(args).enumerate() {
+      if !v.typed_as(t.to_owned()) {
+        return Err(CalxError::new_raw(format!("argument {i} of {name} expected {t:?}, got {v}")));
+      }
+    }
+    Ok(())
+  }
+
+  /// returns values when exactly one is returned for each of `ret_types` of function at `idx`
+  pub(crate) fn check_call_rets(&self, idx: usize, values: Vec<Calx>) -> Result<Vec<Calx>, CalxError> {
+    let name = &self.funcs[idx].name;
+    let ret_size = self.funcs[idx].ret_types.len();
+    if let Some(code) = self.quit_code {
+      return Err(self.gen_err_kind(CalxErrorKind::Quit(code), format!("quit with code {code} in {name}")));
+    }
+    if values.len() != ret_size {
+      return Err(CalxError::new_raw(format!(
+        "function {name} returned {} values, expected {ret_size}",
+        values.len()
+      )));
+    }
+    Ok(values)
+  }
+
+  /// runs function at `idx` until it returns, values left on stack are returned
+  fn run_stack(&mut self, idx: usize, args: Vec<Calx>) -> Result<Vec<Calx>, CalxError> {
+    self.start_top_frame(idx, args);
+    let mut check = self.interrupt_check();
     loop {
       // println!("Stack {:?}", self.stack);
       // println!("-- op {} {:?}", self.stack.len(), instr);
 
       if self.finished {
-        return Ok(self.return_value.to_owned());
+        return Ok(mem::take(&mut self.stack));
+      }
+      if let Some((kind, message)) = check.tick() {
+        return Err(self.gen_err_kind(kind, message));
+      }
+
+      let quick_continue = self.step()?;
+      if quick_continue {
+        continue;
+      }
+
+      self.top_frame.pointer += 1;
+    }
+  }
+
+  /// runs function at `idx` in a new frame above current one, for calls from imports.
+  /// stack and frames of the caller are kept, and restored when the call fails
+  fn run_stack_nested(&mut self, idx: usize, args: Vec<Calx>) -> Result<Vec<Calx>, CalxError> {
+    let depth = self.frames.len();
+    let stack_size = self.stack.len();
+    let pointer = self.top_frame.pointer;
+    let f = &self.funcs[idx];
+    if depth + 1 >= self.max_call_depth {
+      return Err(self.gen_err_kind(
+        CalxErrorKind::CallDepthExceeded,
+        format!("call depth exceeds max_call_depth {} when calling {}", self.max_call_depth, f.name),
+      ));
+    }
+    let mut locals = args;
+    if locals.len() < f.locals_size {
+      locals.resize(f.locals_size, Calx::Nil);
+    }
+    let new_frame = CalxFrame {
+      name: f.name.to_owned(),
+      locals,
+      instrs: f.instrs.to_owned(),
+      pointer: 0,
+      initial_stack_size: stack_size,
+      ret_types: f.ret_types.to_owned(),
+    };
+    let prev_frame = mem::replace(&mut self.top_frame, new_frame);
+    self.frames.push(prev_frame);
+
+    let mut check = self.interrupt_check();
+    let result = loop {
+      // stopped by `quit`
+      if self.finished {
+        break Ok(vec![]);
+      }
+      if let Some((kind, message)) = check.tick() {
+        break Err(self.gen_err_kind(kind, message));
+      }
+      match self.step() {
+        Err(e) => break Err(e),
+        // returned to the frame calling the import
+        Ok(_) if self.frames.len() == depth => break Ok(self.stack.split_off(stack_size)),
+        Ok(true) => {}
+        Ok(false) => self.top_frame.pointer += 1,
+      }
+    };
+
+    if self.frames.len() > depth {
+      self.frames.truncate(depth + 1);
+      self.top_frame = self.frames.pop().expect("caller frame");
+      self.stack.truncate(stack_size);
+    }
+    // returning at function end moves pointer of caller, it moves after the import returns instead
+    self.top_frame.pointer = pointer;
+    result
+  }
+
+  /// like `run`, but pauses with `OutOfFuel` before an instruction costing more than the fuel left
+  pub fn run_with_fuel(&mut self, args: Vec<Calx>, fuel: u64) -> Result<CalxFuelOutcome, CalxError> {
+    if self.engine == CalxEngine::Register {
+      return Err(CalxError::new_raw("fuel metering is only supported by stack engine".to_owned()));
+    }
+    match self.find_func_idx("main") {
+      Some((idx, _)) => self.start_top_frame(idx, args),
+      None => return Err(CalxError::new_raw(String::from("main function is required"))),
+    }
+    self.resume(fuel)
+  }
+
+  /// adds fuel and continues from where `run_with_fuel` or `resume` paused
+  pub fn resume(&mut self, more_fuel: u64) -> Result<CalxFuelOutcome, CalxError> {
+    self.fuel = self.fuel.saturating_add(more_fuel);
+    let mut check = self.interrupt_check();
+    loop {
+      if self.finished {
+        return Ok(match self.quit_code {
+          Some(code) => CalxFuelOutcome::Quit(code),
+          None => CalxFuelOutcome::Finished(self.return_value.to_owned()),
+        });
+      }
+      if let Some((kind, message)) = check.tick() {
+        return Err(self.gen_err_kind(kind, message));
+      }
+
+      // returning at function end costs nothing
+      if let Some(instr) = self.top_frame.instrs.get(self.top_frame.pointer) {
+        let cost = self.fuel_costs.cost_of(instr);
+        if cost > self.fuel {
+          return Ok(CalxFuelOutcome::OutOfFuel);
+        }
+        self.fuel -= cost;
       }
 
       let quick_continue = self.step()?;
@@ -132,6 +401,45 @@ impl CalxVM {
     }
   }
 
+  /// clears state left by a previous run, so that `run` and `call` start over.
+  /// functions, imports, limits and globals are kept, use `reset_globals` for globals
+  pub fn reset(&mut self) {
+    self.stack.clear();
+    self.frames.clear();
+    self.top_frame.pointer = 0;
+    self.top_frame.locals.clear();
+    self.finished = false;
+    self.return_value = Calx::Nil;
+    self.quit_code = None;
+    self.fuel = 0;
+    self.heap_estimate = 0;
+    self.active_imports.clear();
+    self.nested_calls = 0;
+  }
+
+  /// restores globals to the ones given to `new`, dropping globals added by `global.new`
+  pub fn reset_globals(&mut self) {
+    self.globals = self.initial_globals.to_owned();
+  }
+
+  /// fresh frame for function at `idx`, assign function parameters, and other locals start with nil
+  fn start_top_frame(&mut self, idx: usize, args: Vec<Calx>) {
+    self.reset();
+    let f = &self.funcs[idx];
+    let mut locals = args;
+    if locals.len() < f.locals_size {
+      locals.resize(f.locals_size, Calx::Nil);
+    }
+    self.top_frame = CalxFrame {
+      name: f.name.to_owned(),
+      locals,
+      instrs: f.instrs.to_owned(),
+      pointer: 0,
+      initial_stack_size: 0,
+      ret_types: f.ret_types.to_owned(),
+    };
+  }
+
   /// run one step, return true if continuing
   #[inline(always)]
   pub fn step(&mut self) -> Result<bool, CalxError> {
@@ -144,7 +452,8 @@ impl CalxVM {
           self.top_frame = v;
         }
         None => {
-          let v = self.stack.pop().unwrap_or(Calx::Nil);
+          // return values are kept on stack for `call`
+          let v = self.stack.last().cloned().unwrap_or(Calx::Nil);
           self.make_return(v);
           return Ok(false);
         }
@@ -159,25 +468,30 @@ impl CalxVM {
 
     match instr {
       Jmp(line) => {
+        self.check_stack_len()?;
         self.top_frame.pointer = *line;
         return Ok(true); // point reset, goto next loop
       }
       JmpOffset(l) => {
-        self.top_frame.pointer = (self.top_frame.pointer as i32 + l) as usize;
+        self.check_stack_len()?;
+        self.top_frame.pointer = self.offset_target(*l)?;
         return Ok(true); // point reset, goto next loop
       }
       JmpIf(line) => {
-        let v = self.stack.pop().unwrap();
+        let line = *line;
+        let v = self.stack_pop()?;
         if v == Calx::Bool(true) || v == Calx::I64(1) {
-          self.top_frame.pointer = *line;
+          self.check_stack_len()?;
+          self.top_frame.pointer = line;
           return Ok(true); // point reset, goto next loop
         }
       }
       JmpOffsetIf(l) => {
-        self.check_before_pop()?;
-        let v = self.stack.pop().expect("pop value");
+        let l = *l;
+        let v = self.stack_pop()?;
         if v == Calx::Bool(true) || v == Calx::I64(1) {
-          self.top_frame.pointer = (self.top_frame.pointer as i32 + l) as usize;
+          self.check_stack_len()?;
+          self.top_frame.pointer = self.offset_target(l)?;
           return Ok(true); // point reset, goto next loop
         }
       }
@@ -209,7 +523,10 @@ impl CalxVM {
             Calx::F64(n) => self.stack.push(Calx::F64(*n)),
             Calx::Bool(b) => self.stack.push(Calx::Bool(*b)),
             Calx::Nil => self.stack.push(Calx::Nil),
-            _ => self.stack.push(local_val.clone()),
+            _ => {
+              self.stack.push(local_val.clone());
+              self.track_top_heap()?;
+            }
           }
         } else {
           return Err(self.gen_err(format!("invalid index for local.get {idx}")));
@@ -223,14 +540,10 @@ impl CalxVM {
         self.check_func_return(ret_size)?;
 
         if self.frames.is_empty() {
-          // top frame return, just return value
-          return match self.stack.last() {
-            Some(x) => {
-              self.make_return(x.to_owned());
-              Ok(false)
-            }
-            None => Err(self.gen_err("return without value".to_string())),
-          };
+          // top frame return, values are kept on stack for `call`, functions without return values give nil
+          let v = self.stack.last().cloned().unwrap_or(Calx::Nil);
+          self.make_return(v);
+          return Ok(false);
         } else {
           // let prev_frame = self.top_frame;
           self.top_frame = self.frames.pop().unwrap();
@@ -240,7 +553,7 @@ impl CalxVM {
       GlobalSet(idx) => {
         self.check_before_pop()?;
         let v = self.stack.pop().expect("pop value");
-        if self.globals.len() >= *idx {
+        if *idx >= self.globals.len() {
           return Err(self.gen_err(format!("out of bound in global.set {idx}")));
         } else {
           self.globals[*idx] = v
@@ -248,7 +561,8 @@ impl CalxVM {
       }
       GlobalGet(idx) => {
         if *idx < self.globals.len() {
-          self.stack_push(self.globals[*idx].to_owned())
+          self.stack_push(self.globals[*idx].to_owned());
+          self.track_top_heap()?;
         } else {
           return Err(self.gen_err(format!("invalid index for global.get {idx}")));
         }
@@ -261,18 +575,21 @@ impl CalxVM {
           Calx::F64(n) => self.stack.push(Calx::F64(*n)),
           Calx::Bool(b) => self.stack.push(Calx::Bool(*b)),
           Calx::Nil => self.stack.push(Calx::Nil),
+          // shares value held by instruction, no new heap to count
           _ => self.stack.push(v.clone()),
         }
       }
       Dup => {
         // 优化：避免不必要的clone，对于Copy类型直接复制
-        if let Some(last) = self.stack.last() {
-          match last {
-            Calx::I64(n) => self.stack.push(Calx::I64(*n)),
-            Calx::F64(n) => self.stack.push(Calx::F64(*n)),
-            Calx::Bool(b) => self.stack.push(Calx::Bool(*b)),
-            Calx::Nil => self.stack.push(Calx::Nil),
-            _ => self.stack.push(last.clone()),
+        let last = &self.stack[self.stack_top_idx()?];
+        match last {
+          Calx::I64(n) => self.stack.push(Calx::I64(*n)),
+          Calx::F64(n) => self.stack.push(Calx::F64(*n)),
+          Calx::Bool(b) => self.stack.push(Calx::Bool(*b)),
+          Calx::Nil => self.stack.push(Calx::Nil),
+          _ => {
+            self.stack.push(last.clone());
+            self.track_top_heap()?;
           }
         }
       }
@@ -281,71 +598,92 @@ impl CalxVM {
       }
       IntAdd => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         if let (Calx::I64(n1), Calx::I64(n2)) = (&self.stack[last_idx], &v2) {
-          self.stack[last_idx] = Calx::I64(n1 + n2);
+          match n1.checked_add(*n2) {
+            Some(n) => self.stack[last_idx] = Calx::I64(n),
+            None => return Err(self.gen_err(format!("integer overflow when adding {n1} and {n2}"))),
+          }
         } else {
           return Err(self.gen_err(format!("expected 2 integers to add, {:?} {:?}", self.stack[last_idx], v2)));
         }
       }
       IntMul => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         if let (Calx::I64(n1), Calx::I64(n2)) = (&self.stack[last_idx], &v2) {
-          self.stack[last_idx] = Calx::I64(n1 * n2);
+          match n1.checked_mul(*n2) {
+            Some(n) => self.stack[last_idx] = Calx::I64(n),
+            None => return Err(self.gen_err(format!("integer overflow when multiplying {n1} and {n2}"))),
+          }
         } else {
           return Err(self.gen_err(format!("expected 2 integers to multiply, {:?} {:?}", self.stack[last_idx], v2)));
         }
       }
       IntDiv => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         if let (Calx::I64(n1), Calx::I64(n2)) = (&self.stack[last_idx], &v2) {
-          self.stack[last_idx] = Calx::I64(n1 / n2);
+          match n1.checked_div(*n2) {
+            Some(n) => self.stack[last_idx] = Calx::I64(n),
+            None => return Err(self.gen_err(format!("failed to divide {n1} by {n2}"))),
+          }
         } else {
           return Err(self.gen_err(format!("expected 2 integers to divide, {:?} {:?}", self.stack[last_idx], v2)));
         }
       }
       IntRem => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         if let (Calx::I64(n1), Calx::I64(n2)) = (&self.stack[last_idx], &v2) {
-          self.stack[last_idx] = Calx::I64((*n1).rem(n2));
+          match n1.checked_rem(*n2) {
+            Some(n) => self.stack[last_idx] = Calx::I64(n),
+            None => return Err(self.gen_err(format!("failed to get remainder of {n1} by {n2}"))),
+          }
         } else {
           return Err(self.gen_err(format!("expected 2 integers for remainder, {:?} {:?}", self.stack[last_idx], v2)));
         }
       }
       IntNeg => {
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
         if let Calx::I64(n) = self.stack[last_idx] {
-          self.stack[last_idx] = Calx::I64(-n)
+          match n.checked_neg() {
+            Some(n) => self.stack[last_idx] = Calx::I64(n),
+            None => return Err(self.gen_err(format!("integer overflow when negating {n}"))),
+          }
         } else {
           return Err(self.gen_err(format!("expected int, got {}", self.stack[last_idx])));
         }
       }
       IntShr => {
         let bits = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
         match (&self.stack[last_idx], &bits) {
-          (Calx::I64(n), Calx::I64(b)) => self.stack[last_idx] = Calx::I64(n.checked_shr(*b as u32).unwrap()),
+          (Calx::I64(n), Calx::I64(b)) => match n.checked_shr(*b as u32) {
+            Some(v) => self.stack[last_idx] = Calx::I64(v),
+            None => return Err(self.gen_err(format!("invalid number for SHR, {n:?} {b:?}"))),
+          },
           (_, _) => return Err(self.gen_err(format!("invalid number for SHR, {:?} {:?}", self.stack[last_idx], bits))),
         }
       }
       IntShl => {
         let bits = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
         match (&self.stack[last_idx], &bits) {
-          (Calx::I64(n), Calx::I64(b)) => self.stack[last_idx] = Calx::I64(n.checked_shl(*b as u32).unwrap()),
+          (Calx::I64(n), Calx::I64(b)) => match n.checked_shl(*b as u32) {
+            Some(v) => self.stack[last_idx] = Calx::I64(v),
+            None => return Err(self.gen_err(format!("invalid number for SHL, {n:?} {b:?}"))),
+          },
           (_, _) => return Err(self.gen_err(format!("invalid number for SHL, {:?} {:?}", self.stack[last_idx], bits))),
         }
       }
       IntEq => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         if let (Calx::I64(n1), Calx::I64(n2)) = (&self.stack[last_idx], &v2) {
           self.stack[last_idx] = Calx::Bool(n1 == n2);
@@ -356,7 +694,7 @@ impl CalxVM {
 
       IntNe => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
         match (&self.stack[last_idx], &v2) {
           (Calx::I64(n1), Calx::I64(n2)) => self.stack[last_idx] = Calx::Bool(n1 != n2),
           (_, _) => return Err(self.gen_err(format!("expected 2 integers to ne compare, {:?} {:?}", self.stack[last_idx], v2))),
@@ -364,7 +702,7 @@ impl CalxVM {
       }
       IntLt => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         if let (Calx::I64(n1), Calx::I64(n2)) = (&self.stack[last_idx], &v2) {
           self.stack[last_idx] = Calx::Bool(n1 < n2);
@@ -374,7 +712,7 @@ impl CalxVM {
       }
       IntLe => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         if let (Calx::I64(n1), Calx::I64(n2)) = (&self.stack[last_idx], &v2) {
           self.stack[last_idx] = Calx::Bool(n1 <= n2);
@@ -384,7 +722,7 @@ impl CalxVM {
       }
       IntGt => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         if let (Calx::I64(n1), Calx::I64(n2)) = (&self.stack[last_idx], &v2) {
           self.stack[last_idx] = Calx::Bool(n1 > n2);
@@ -394,7 +732,7 @@ impl CalxVM {
       }
       IntGe => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         match (&self.stack[last_idx], &v2) {
           (Calx::I64(n1), Calx::I64(n2)) => self.stack[last_idx] = Calx::Bool(n1 >= n2),
@@ -403,28 +741,34 @@ impl CalxVM {
       }
       Add => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         match (&self.stack[last_idx], &v2) {
           (Calx::F64(n1), Calx::F64(n2)) => self.stack[last_idx] = Calx::F64(n1 + n2),
-          (Calx::I64(n1), Calx::I64(n2)) => self.stack[last_idx] = Calx::I64(n1 + n2),
+          (Calx::I64(n1), Calx::I64(n2)) => match n1.checked_add(*n2) {
+            Some(n) => self.stack[last_idx] = Calx::I64(n),
+            None => return Err(self.gen_err(format!("integer overflow when adding {n1} and {n2}"))),
+          },
           (_, _) => return Err(self.gen_err(format!("expected 2 numbers to +, {:?} {:?}", self.stack[last_idx], v2))),
         }
       }
       Mul => {
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         match (&self.stack[last_idx], &v2) {
           (Calx::F64(n1), Calx::F64(n2)) => self.stack[last_idx] = Calx::F64(n1 * n2),
-          (Calx::I64(n1), Calx::I64(n2)) => self.stack[last_idx] = Calx::I64(n1 * n2),
+          (Calx::I64(n1), Calx::I64(n2)) => match n1.checked_mul(*n2) {
+            Some(n) => self.stack[last_idx] = Calx::I64(n),
+            None => return Err(self.gen_err(format!("integer overflow when multiplying {n1} and {n2}"))),
+          },
           (_, _) => return Err(self.gen_err(format!("expected 2 numbers to multiply, {:?} {:?}", self.stack[last_idx], v2))),
         }
       }
       Div => {
         // reversed order
         let v2 = self.stack_pop()?;
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
 
         match (&self.stack[last_idx], &v2) {
           (Calx::F64(n1), Calx::F64(n2)) => self.stack[last_idx] = Calx::F64(n1 / n2),
@@ -432,45 +776,39 @@ impl CalxVM {
         }
       }
       Neg => {
-        let last_idx = self.stack.len() - 1;
+        let last_idx = self.stack_top_idx()?;
         if let Calx::F64(n) = self.stack[last_idx] {
           self.stack[last_idx] = Calx::F64(-n)
         } else {
           return Err(self.gen_err(format!("expected float, got {}", self.stack[last_idx])));
         }
       }
-      NewList => {
-        todo!()
-      }
-      ListGet => {
-        todo!()
-      }
-      ListSet => {
-        todo!()
-      }
-      NewLink => {
-        todo!()
-      }
-      And => {
-        todo!()
-      }
-      Or => {
-        todo!()
-      }
-      Not => {
-        todo!()
+      NewList | ListGet | ListSet | NewLink | And | Or | Not => {
+        return Err(self.gen_err(format!("instruction not implemented yet: {instr:?}")));
       }
       Call(idx) => {
         // println!("frame size: {}", self.frames.len());
-        let f = &self.funcs[*idx];
+        let f = match self.funcs.get(*idx) {
+          Some(f) => f,
+          None => return Err(self.gen_err(format!("unknown function #{idx}"))),
+        };
+        if self.frames.len() + 1 >= self.max_call_depth {
+          return Err(self.gen_err_kind(
+            CalxErrorKind::CallDepthExceeded,
+            format!("call depth exceeds max_call_depth {} when calling {}", self.max_call_depth, f.name),
+          ));
+        }
+        self.check_stack_len()?;
         let instrs = &f.instrs;
         let ret_types = f.ret_types.clone();
         let f_name = f.name.clone();
 
         let n = f.params_types.len();
+        let locals_size = f.locals_size.max(n);
         self.check_before_pop_n(n)?;
         let next_size = self.stack.len() - n;
-        let locals = self.stack.split_off(next_size);
+        let mut locals = self.stack.split_off(next_size);
+        locals.resize(locals_size, Calx::Nil);
 
         // TODO reduce copy drop
 
@@ -490,7 +828,11 @@ impl CalxVM {
       }
       ReturnCall(idx) => {
         // println!("frame size: {}", self.frames.len());
-        let f = &self.funcs[*idx];
+        self.check_stack_len()?;
+        let f = match self.funcs.get(*idx) {
+          Some(f) => f,
+          None => return Err(self.gen_err(format!("unknown function #{idx}"))),
+        };
 
         // println!("examine stack: {:?}", self.stack);
         let instrs = &f.instrs;
@@ -498,10 +840,12 @@ impl CalxVM {
         let f_name = f.name.clone();
 
         let n = f.params_types.len();
+        let locals_size = f.locals_size.max(n);
         self.check_before_pop_n(n)?;
 
         let next_size = self.stack.len() - n;
-        let locals = self.stack.split_off(next_size);
+        let mut locals = self.stack.split_off(next_size);
+        locals.resize(locals_size, Calx::Nil);
 
         let prev_frame = &self.top_frame;
         if prev_frame.initial_stack_size != next_size {
@@ -523,33 +867,34 @@ impl CalxVM {
         // start in new frame
         return Ok(true);
       }
-      CallImport(f_name) => match self.imports.get(f_name) {
-        None => return Err(self.gen_err(format!("missing imported function {f_name}"))),
-        Some((f, size)) => {
-          if self.stack.len() < *size {
-            return Err(self.gen_err(format!(
-              "imported function {} expected {} arguemtns, found {} on stack",
-              f_name,
-              size,
-              self.stack.len()
-            )));
-          }
-
-          let n = *size;
+      CallImport(idx) => match self.import_table.get(*idx) {
+        None => return Err(self.gen_err(format!("missing imported function #{idx}"))),
+        Some(import) => {
+          let (idx, n, ret_size) = (*idx, import.arity, import.ret_size());
           self.check_before_pop_n(n)?;
           let args = self.stack.split_off(self.stack.len() - n);
 
-          let v = f(&args)?;
-          self.stack_push(v);
+          let result = self.call_import(idx, &args);
+          // `quit` in a function called from the import
+          if self.quit_code.is_some() {
+            self.finished = true;
+            return Ok(false);
+          }
+          let v = result?;
+          if ret_size > 0 {
+            self.stack_push(v);
+            self.track_top_heap()?;
+          }
         }
       },
-      Unreachable => {
-        unreachable!("Unexpected from op")
-      }
+      Unreachable => return Err(self.gen_err_kind(CalxErrorKind::Unreachable, String::from("reached unreachable"))),
       Nop => {
         // Noop
       }
-      Quit(code) => std::process::exit(*code as i32),
+      Quit(code) => {
+        self.quit_code = Some(*code);
+        self.finished = true;
+      }
       Echo => {
         let v = self.stack_pop()?;
         println!("{v}");
@@ -567,12 +912,57 @@ impl CalxVM {
         println!("[ ----------------{}", self.inspect_display(2));
         println!("  -------------- ]");
       }
+      IntAddLocals(a, b) => match (self.top_frame.locals.get(*a), self.top_frame.locals.get(*b)) {
+        (Some(Calx::I64(n1)), Some(Calx::I64(n2))) => match n1.checked_add(*n2) {
+          Some(n) => self.stack.push(Calx::I64(n)),
+          None => return Err(self.gen_err(format!("integer overflow when adding {n1} and {n2}"))),
+        },
+        (Some(v1), Some(v2)) => return Err(self.gen_err(format!("expected 2 integers to add, {v1:?} {v2:?}"))),
+        (_, _) => return Err(self.gen_err(format!("invalid index for local.get {a} {b}"))),
+      },
+      IntAddLocalConst(idx, k) => match self.top_frame.locals.get(*idx) {
+        Some(Calx::I64(n)) => match n.checked_add(*k) {
+          Some(v) => self.stack.push(Calx::I64(v)),
+          None => return Err(self.gen_err(format!("integer overflow when adding {n} and {k}"))),
+        },
+        Some(v) => return Err(self.gen_err(format!("expected 2 integers to add, {v:?} {k:?}"))),
+        None => return Err(self.gen_err(format!("invalid index for local.get {idx}"))),
+      },
+      LocalIncrease(idx, k) => match self.top_frame.locals.get_mut(*idx) {
+        Some(Calx::I64(n)) => match n.checked_add(*k) {
+          Some(v) => *n = v,
+          None => {
+            let message = format!("integer overflow when adding {n} and {k}");
+            return Err(self.gen_err(message));
+          }
+        },
+        Some(v) => {
+          let message = format!("expected 2 integers to add, {v:?} {k:?}");
+          return Err(self.gen_err(message));
+        }
+        None => return Err(self.gen_err(format!("invalid index for local.get {idx}"))),
+      },
+      JmpIfLocalConst { local, cmp, value, to } => match self.top_frame.locals.get(*local) {
+        Some(Calx::I64(n)) => {
+          if cmp.eval(*n, *value) {
+            self.check_stack_len()?;
+            self.top_frame.pointer = *to;
+            return Ok(true); // point reset, goto next loop
+          }
+        }
+        Some(v) => return Err(self.gen_err(format!("expected 2 integers to {cmp:?} compare, {v:?} {value:?}"))),
+        None => return Err(self.gen_err(format!("invalid index for local.get {local}"))),
+      },
     }
 
     Ok(false)
   }
 
   pub fn preprocess(&mut self, verbose: bool) -> Result<(), String> {
+    // `imports` and `import_decls` might be changed after `new`, instructions are generated again with a sorted table
+    self.import_table.clear();
+    self.rebuild_import_table();
+    self.check_import_decls()?;
     for i in 0..self.funcs.len() {
       let mut stack_size = 0;
       let mut ops: Vec<CalxInstr> = vec![];
@@ -697,13 +1087,14 @@ impl CalxVM {
             }
             None => return Err(format!("cannot find function named: {f_name}")),
           },
-          CalxSyntax::CallImport(f_name) => match &self.imports.get(f_name) {
-            Some((_f, size)) => {
-              if stack_size < *size {
+          CalxSyntax::CallImport(f_name) => match find_import(&self.import_table, f_name) {
+            Some(idx) => {
+              let size = self.import_table[idx].arity;
+              if stack_size < size {
                 return Err(format!("insufficient size to call import: {stack_size} {size:?}"));
               }
-              stack_size = stack_size - size + 1;
-              ops.push(CalxInstr::CallImport(f_name.to_owned()))
+              stack_size = stack_size - size + self.import_table[idx].ret_size();
+              ops.push(CalxInstr::CallImport(idx))
             }
             None => return Err(format!("missing imported function {f_name}")),
           },
@@ -745,7 +1136,13 @@ impl CalxVM {
             }
 
             match prev_block {
-              BlockData::If { to, .. } => ops.push(CalxInstr::Jmp(*to)),
+              BlockData::If {
+                to, initial_stack_size, ..
+              } => {
+                ops.push(CalxInstr::Jmp(*to));
+                // then branch starts with the same stack as else branch
+                stack_size = initial_stack_size - 1;
+              }
               _ => unreachable!("end inside if"),
             }
           }
@@ -764,6 +1161,8 @@ impl CalxVM {
               _ => unreachable!("end inside if"),
             }
           }
+          // locals are allocated at call by `locals_size`
+          CalxSyntax::LocalNew => ops.push(CalxInstr::Nop),
           a => {
             let instr: CalxInstr = a.try_into()?;
             // checks
@@ -787,15 +1186,22 @@ impl CalxVM {
         ));
       }
 
+      self.funcs[i].syntax_map = Rc::new((0..ops.len()).collect());
       self.funcs[i].instrs = Rc::new(ops);
+      self.funcs[i].locals_size = self.funcs[i].count_locals();
     }
 
     Ok(())
   }
 
+  /// verify instructions without trusting them, useful when `instrs` are not generated by `preprocess`
+  pub fn verify(&self) -> Result<(), Vec<CalxVerifyError>> {
+    verifier::verify_funcs(&self.funcs, self.globals.len(), &self.import_table)
+  }
+
   #[inline(always)]
   fn check_func_return(&self, ret_size: usize) -> Result<(), CalxError> {
-    if self.stack.len() - ret_size != self.top_frame.initial_stack_size {
+    if self.stack.len() != self.top_frame.initial_stack_size + ret_size {
       return Err(self.gen_err(format!(
         "stack size {} does not fit initial size {} plus {:?}",
         self.stack.len(),
@@ -818,6 +1224,26 @@ impl CalxVM {
     }
   }
 
+  /// index of top value in current frame, without popping it
+  #[inline(always)]
+  fn stack_top_idx(&self) -> Result<usize, CalxError> {
+    if self.stack.len() <= self.top_frame.initial_stack_size {
+      Err(self.gen_err(String::from("cannot read from parent stack")))
+    } else {
+      Ok(self.stack.len() - 1)
+    }
+  }
+
+  /// pointer after a relative jump, jumping to end of function is allowed
+  fn offset_target(&self, offset: i32) -> Result<usize, CalxError> {
+    let target = self.top_frame.pointer as i64 + offset as i64;
+    if target < 0 || target > self.top_frame.instrs.len() as i64 {
+      Err(self.gen_err(format!("jump offset {offset} out of function")))
+    } else {
+      Ok(target as usize)
+    }
+  }
+
   fn check_before_pop(&self) -> Result<(), CalxError> {
     if self.stack.is_empty() {
       return Err(self.gen_err(String::from("cannot pop from empty stack")));
@@ -842,14 +1268,51 @@ impl CalxVM {
   }
 
   fn gen_err(&self, s: String) -> CalxError {
+    self.gen_err_kind(CalxErrorKind::Runtime, s)
+  }
+
+  fn gen_err_kind(&self, kind: CalxErrorKind, s: String) -> CalxError {
     CalxError {
+      kind,
       message: s,
       top_frame: self.top_frame.to_owned(),
       stack: self.stack.to_owned(),
       globals: self.globals.to_owned(),
+      syntax_index: self.find_func(&self.top_frame.name).map(|f| f.syntax_index(self.top_frame.pointer)),
+      backtrace: self.backtrace(),
     }
   }
 
+  /// names of functions in frames, from top frame to `main`
+  fn backtrace(&self) -> Vec<Rc<str>> {
+    let mut names = vec![self.top_frame.name.to_owned()];
+    names.extend(self.frames.iter().rev().map(|x| x.name.to_owned()));
+    names
+  }
+
+  /// counts value on top of stack against `max_memory`
+  #[inline(always)]
+  fn track_top_heap(&mut self) -> Result<(), CalxError> {
+    if self.max_memory.is_some() {
+      let bytes = self.stack.last().map_or(0, Calx::heap_size);
+      if let Some((kind, message)) = self.track_heap(bytes, |vm| vm.memory_usage().total()) {
+        return Err(self.gen_err_kind(kind, message));
+      }
+    }
+    Ok(())
+  }
+
+  #[inline(always)]
+  fn check_stack_len(&self) -> Result<(), CalxError> {
+    if self.stack.len() > self.max_stack_len {
+      return Err(self.gen_err_kind(
+        CalxErrorKind::StackOverflow,
+        format!("stack length {} exceeds max_stack_len {}", self.stack.len(), self.max_stack_len),
+      ));
+    }
+    Ok(())
+  }
+
   fn find_func(&self, name: &str) -> Option<&CalxFunc> {
     self.funcs.iter().find(|x| &*x.name == name)
   }
@@ -861,25 +1324,95 @@ impl CalxVM {
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct CalxError {
+  pub kind: CalxErrorKind,
   pub message: String,
   pub stack: Vec<Calx>,
   pub top_frame: CalxFrame,
   pub globals: Vec<Calx>,
+  /// position in `syntax` of the failed instruction
+  pub syntax_index: Option<usize>,
+  /// names of functions in frames, from top frame to `main`
+  pub backtrace: Vec<Rc<str>>,
+}
+
+/// what stopped the VM, VM state is kept in the error for inspection
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub enum CalxErrorKind {
+  /// failure of an instruction, or of preparing to run
+  #[default]
+  Runtime,
+  /// `CalxInterruptHandle` was triggered
+  Interrupted,
+  /// `timeout` of VM passed
+  Timeout,
+  /// frames reached `max_call_depth`
+  CallDepthExceeded,
+  /// values on stack beyond `max_stack_len`
+  StackOverflow,
+  /// strings and lists hold more bytes than `max_memory`
+  MemoryExceeded,
+  /// `unreachable` instruction was run
+  Unreachable,
+  /// `quit` with a code in a function entered with `call` or `CalxImportCtx::call`, it quits the caller too
+  Quit(usize),
+}
+
+/// how running of `main` ended, returned from `run`
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum CalxRunOutcome {
+  Returned(Calx),
+  /// `quit` with a code, for host to use as exit code
+  Quit(usize),
+  /// stopped by an error, kind is same as in the error
+  Trap(CalxErrorKind, CalxError),
+}
+
+impl CalxRunOutcome {
+  /// value from `Returned`, other outcomes are turned into errors
+  pub fn into_result(self) -> Result<Calx, CalxError> {
+    match self {
+      Self::Returned(v) => Ok(v),
+      Self::Quit(code) => Err(CalxError {
+        kind: CalxErrorKind::Quit(code),
+        ..CalxError::new_raw(format!("quit with code {code}"))
+      }),
+      Self::Trap(_, e) => Err(e),
+    }
+  }
 }
 
 impl fmt::Display for CalxError {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "{}\n{:?}\n{}", self.message, self.stack, self.top_frame)
+    write!(f, "{}\n{:?}\n{}", self.message, self.stack, self.top_frame)?;
+    if let Some(idx) = self.syntax_index {
+      write!(f, "\nat syntax {idx}")?;
+    }
+    // deep recursion is shown by a few frames at both ends
+    if self.backtrace.len() > 1 {
+      f.write_str("\nbacktrace:")?;
+      let size = self.backtrace.len();
+      for (idx, name) in self.backtrace.iter().enumerate() {
+        if idx < 8 || idx >= size - 8 {
+          write!(f, " {name}")?;
+        } else if idx == 8 {
+          write!(f, " ...({} more)", size - 16)?;
+        }
+      }
+    }
+    Ok(())
   }
 }
 
 impl CalxError {
   pub fn new_raw(s: String) -> Self {
     CalxError {
+      kind: CalxErrorKind::Runtime,
       message: s,
       stack: vec![],
       top_frame: CalxFrame::default(),
       globals: vec![],
+      syntax_index: None,
+      backtrace: vec![],
     }
   }
 }
@@ -1,4 +1,4 @@
-use std::rc::Rc;
+use crate::rc::Rc;
 
 use crate::calx::CalxType;
 
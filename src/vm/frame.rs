@@ -1,5 +1,5 @@
+use crate::rc::Rc;
 use core::fmt;
-use std::rc::Rc;
 
 use crate::calx::{Calx, CalxType};
 
@@ -0,0 +1,45 @@
+use bincode::{Decode, Encode};
+
+use crate::calx::Calx;
+
+use super::instr::CalxInstr;
+
+/// result of `run_with_fuel` and `resume`
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum CalxFuelOutcome {
+  Finished(Calx),
+  /// finished by `quit` with a code
+  Quit(usize),
+  /// paused before an instruction that costs more than the fuel left, continue with `resume`
+  OutOfFuel,
+}
+
+/// fuel consumed by instructions, calls into functions and imports cost more by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Decode, Encode)]
+pub struct CalxFuelCosts {
+  /// cost of instructions not listed below
+  pub instr: u64,
+  /// `call` and `return-call`
+  pub call: u64,
+  pub call_import: u64,
+}
+
+impl Default for CalxFuelCosts {
+  fn default() -> Self {
+    CalxFuelCosts {
+      instr: 1,
+      call: 10,
+      call_import: 20,
+    }
+  }
+}
+
+impl CalxFuelCosts {
+  pub fn cost_of(&self, instr: &CalxInstr) -> u64 {
+    match instr {
+      CalxInstr::Call(_) | CalxInstr::ReturnCall(_) => self.call,
+      CalxInstr::CallImport(_) => self.call_import,
+      _ => self.instr,
+    }
+  }
+}
@@ -1,18 +1,53 @@
+use crate::rc::Rc;
+use bincode::{Decode, Encode};
 use core::fmt;
-use std::rc::Rc;
 
 use crate::{calx::CalxType, syntax::CalxSyntax};
 
 use super::instr::CalxInstr;
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq, PartialOrd, Decode, Encode)]
 pub struct CalxFunc {
   pub name: Rc<str>,
   pub params_types: Rc<Vec<CalxType>>,
   pub ret_types: Rc<Vec<CalxType>>,
   pub syntax: Rc<Vec<CalxSyntax>>,
   pub instrs: Rc<Vec<CalxInstr>>,
+  /// position in `syntax` for each instruction, for error messages after instructions are moved by optimizations
+  pub syntax_map: Rc<Vec<usize>>,
   pub local_names: Rc<Vec<String>>,
+  /// number of locals including params, slots after params are created with nil when function is called
+  pub locals_size: usize,
+  pub inline_hint: CalxInlineHint,
+}
+
+/// from `inline` or `noinline` after types in `fn` header
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Decode, Encode)]
+pub enum CalxInlineHint {
+  /// inlined when function is small enough
+  #[default]
+  Auto,
+  Always,
+  Never,
+}
+
+impl CalxFunc {
+  /// position in `syntax` of instruction at `pointer`, same position when there's no mapping
+  pub fn syntax_index(&self, pointer: usize) -> usize {
+    self.syntax_map.get(pointer).copied().unwrap_or(pointer)
+  }
+
+  /// locals needed by params, by `locals_size`, and by every local used in instructions
+  pub fn count_locals(&self) -> usize {
+    let mut size = self.locals_size.max(self.params_types.len());
+    for instr in self.instrs.iter() {
+      instr.to_owned().map_locals(|i| {
+        size = size.max(i + 1);
+        i
+      });
+    }
+    size
+  }
 }
 
 impl fmt::Display for CalxFunc {
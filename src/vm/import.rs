@@ -0,0 +1,341 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::{fmt, mem};
+
+use bincode::{Decode, Encode};
+
+use crate::rc::{borrow_cell, rc_cell, MaybeSend, Rc, RcCell};
+
+use crate::calx::{Calx, CalxType};
+
+use super::{register::CalxEngine, CalxError, CalxErrorKind, CalxImportsDict, CalxVM};
+
+/// calls from imports into functions running imports again, each level takes some native stack
+const CALX_MAX_NESTED_CALLS: usize = 200;
+
+/// closure imported with `CalxVM::add_import`, it may hold host state like counters or buffers
+pub trait CalxImportClosure: FnMut(&mut CalxImportCtx, &[Calx]) -> Result<Calx, CalxError> + MaybeSend {}
+
+impl<F: FnMut(&mut CalxImportCtx, &[Calx]) -> Result<Calx, CalxError> + MaybeSend> CalxImportClosure for F {}
+
+/// imported closures by name with arity, shared by clones of a VM
+pub(crate) type CalxImportClosures = HashMap<Rc<str>, (RcCell<dyn CalxImportClosure>, usize)>;
+
+/// types of arguments and return value of an import, imports return at most one value
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Decode, Encode)]
+pub struct CalxImportSig {
+  pub params: Vec<CalxType>,
+  pub rets: Vec<CalxType>,
+}
+
+impl fmt::Display for CalxImportSig {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("(")?;
+    for t in &self.params {
+      write!(f, "{t:?} ")?;
+    }
+    f.write_str("->")?;
+    for t in &self.rets {
+      write!(f, " {t:?}")?;
+    }
+    f.write_str(")")
+  }
+}
+
+/// `import <name> (<params> -> <rets>)` in source, checked against host registration in `preprocess`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Decode, Encode)]
+pub struct CalxImportDecl {
+  pub name: Rc<str>,
+  pub sig: CalxImportSig,
+}
+
+#[derive(Clone)]
+pub enum CalxImportFn {
+  /// function from `CalxImportsDict`
+  Plain(fn(xs: &Vec<Calx>) -> Result<Calx, CalxError>),
+  Closure(RcCell<dyn CalxImportClosure>),
+}
+
+impl fmt::Debug for CalxImportFn {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CalxImportFn::Plain(_) => f.write_str("Plain"),
+      CalxImportFn::Closure(_) => f.write_str("Closure"),
+    }
+  }
+}
+
+/// imported function resolved from `CalxImportsDict` or closures, `CallImport` refers to it by index in the import table
+#[derive(Debug, Clone)]
+pub struct CalxImport {
+  /// only used in diagnostics
+  pub name: Rc<str>,
+  pub f: CalxImportFn,
+  /// number of values popped from stack as arguments
+  pub arity: usize,
+  /// registered by host or declared in source, arguments and return value are checked when present
+  pub sig: Option<CalxImportSig>,
+}
+
+impl CalxImport {
+  /// values pushed to stack, imports without a signature always push one
+  pub fn ret_size(&self) -> usize {
+    self.sig.as_ref().map_or(1, |sig| sig.rets.len())
+  }
+}
+
+/// imports sorted by name, so indexes do not depend on order of hashing.
+/// a closure replaces a function of the same name
+pub(crate) fn import_table(imports: &CalxImportsDict, closures: &CalxImportClosures) -> Vec<CalxImport> {
+  let mut table: Vec<CalxImport> = imports
+    .iter()
+    .filter(|(name, _)| !closures.contains_key(*name))
+    .map(|(name, (f, arity))| CalxImport {
+      name: name.to_owned(),
+      f: CalxImportFn::Plain(*f),
+      arity: *arity,
+      sig: None,
+    })
+    .collect();
+  for (name, (f, arity)) in closures {
+    table.push(CalxImport {
+      name: name.to_owned(),
+      f: CalxImportFn::Closure(f.to_owned()),
+      arity: *arity,
+      sig: None,
+    });
+  }
+  table.sort_by(|a, b| a.name.cmp(&b.name));
+  table
+}
+
+/// index of import in a table from `import_table`, names added after `preprocess` are not sorted
+pub fn find_import(table: &[CalxImport], name: &str) -> Option<usize> {
+  table.iter().position(|x| *x.name == *name)
+}
+
+/// closure returning an error, keeps index of an import that is no longer callable
+pub(crate) fn placeholder_closure(message: String) -> RcCell<dyn CalxImportClosure> {
+  rc_cell(move |_: &mut CalxImportCtx, _: &[Calx]| Err(CalxError::new_raw(message.to_owned())))
+}
+
+#[cfg(not(feature = "sync"))]
+type CalxUserDataBox = Box<dyn Any>;
+
+#[cfg(feature = "sync")]
+type CalxUserDataBox = Box<dyn Any + Send + Sync>;
+
+/// host value kept by VM for imported closures, it's not copied when VM is cloned or snapshotted
+#[derive(Default)]
+pub(crate) struct CalxUserData(Option<CalxUserDataBox>);
+
+impl Clone for CalxUserData {
+  fn clone(&self) -> Self {
+    CalxUserData(None)
+  }
+}
+
+/// access to VM from imported closures
+pub struct CalxImportCtx<'a> {
+  vm: &'a mut CalxVM,
+}
+
+impl CalxImportCtx<'_> {
+  pub fn globals(&self) -> &[Calx] {
+    &self.vm.globals
+  }
+
+  pub fn globals_mut(&mut self) -> &mut Vec<Calx> {
+    &mut self.vm.globals
+  }
+
+  /// value set by `CalxVM::set_user_data`, `None` when missing or in another type
+  pub fn user_data<T: Any>(&mut self) -> Option<&mut T> {
+    self.vm.user_data_mut::<T>()
+  }
+
+  /// calls a function by name on the same VM, for example a comparator in a sort provided by host.
+  /// returns after the function returns, and the running function continues after the import
+  pub fn call(&mut self, name: &str, args: &[Calx]) -> Result<Vec<Calx>, CalxError> {
+    match self.vm.find_func_idx(name) {
+      Some((idx, _)) => self.vm.call_nested(idx, args),
+      None => Err(CalxError::new_raw(format!("unknown function {name}"))),
+    }
+  }
+
+  /// like `call`, with index of function in `funcs`
+  pub fn call_at(&mut self, idx: usize, args: &[Calx]) -> Result<Vec<Calx>, CalxError> {
+    self.vm.call_nested(idx, args)
+  }
+}
+
+impl CalxVM {
+  /// imports a closure, which replaces an import of the same name.
+  /// indexes of imports are resolved in `preprocess`, so new names are only callable after preprocessing again.
+  /// names already in the import table keep their indexes, new names are appended
+  pub fn add_import<F: CalxImportClosure + 'static>(&mut self, name: &str, arity: usize, f: F) {
+    let f: RcCell<dyn CalxImportClosure> = rc_cell(f);
+    self.import_closures.insert(Rc::from(name), (f, arity));
+    self.import_sigs.remove(name);
+    self.rebuild_import_table();
+  }
+
+  /// like `add_import`, with types checked against `import` declarations in `preprocess` and at each call
+  pub fn add_typed_import<F: CalxImportClosure + 'static>(&mut self, name: &str, sig: CalxImportSig, f: F) {
+    let f: RcCell<dyn CalxImportClosure> = rc_cell(f);
+    self.import_closures.insert(Rc::from(name), (f, sig.params.len()));
+    self.import_sigs.insert(Rc::from(name), sig);
+    self.rebuild_import_table();
+  }
+
+  /// adds types to a function in `imports`
+  pub fn set_import_sig(&mut self, name: &str, sig: CalxImportSig) {
+    self.import_sigs.insert(Rc::from(name), sig);
+    self.rebuild_import_table();
+  }
+
+  /// signature of each import is taken from host, or from declaration in source when host has none.
+  /// since instructions refer to imports by index, names keep their indexes, and names no longer provided return errors,
+  /// `preprocess` clears the table first so that it's sorted again
+  pub(crate) fn rebuild_import_table(&mut self) {
+    // lowered calls depend on arities and return sizes of imports
+    self.reg_cache = None;
+    let mut fresh = import_table(&self.imports, &self.import_closures);
+    let mut table: Vec<CalxImport> = Vec::with_capacity(fresh.len());
+    for prev in &self.import_table {
+      match fresh.iter().position(|x| x.name == prev.name) {
+        Some(i) => table.push(fresh.remove(i)),
+        None => table.push(CalxImport {
+          f: CalxImportFn::Closure(placeholder_closure(format!("import {} is no longer provided", prev.name))),
+          ..prev.to_owned()
+        }),
+      }
+    }
+    table.extend(fresh);
+    for import in &mut table {
+      import.sig = match self.import_sigs.get(&import.name) {
+        Some(sig) => Some(sig.to_owned()),
+        None => self.import_decls.iter().find(|x| x.name == import.name).map(|x| x.sig.to_owned()),
+      };
+    }
+    self.import_table = table;
+  }
+
+  /// `import` declarations in source should fit imports registered by host
+  pub(crate) fn check_import_decls(&self) -> Result<(), String> {
+    for decl in &self.import_decls {
+      let name = &decl.name;
+      if decl.sig.rets.len() > 1 {
+        return Err(format!("import {name} declared with {}, imports return at most 1 value", decl.sig));
+      }
+      let import = match find_import(&self.import_table, name) {
+        Some(idx) => &self.import_table[idx],
+        None => return Err(format!("import {name} declared as {}, but not provided by host", decl.sig)),
+      };
+      if import.arity != decl.sig.params.len() {
+        return Err(format!(
+          "import {name} declared as {}, but host registered {} params",
+          decl.sig, import.arity
+        ));
+      }
+      if let Some(sig) = self.import_sigs.get(name) {
+        if sig != &decl.sig {
+          return Err(format!("import {name} declared as {}, but host registered {sig}", decl.sig));
+        }
+      }
+    }
+    for (name, sig) in &self.import_sigs {
+      if sig.rets.len() > 1 {
+        return Err(format!("import {name} registered with {sig}, imports return at most 1 value"));
+      }
+      match find_import(&self.import_table, name) {
+        Some(idx) if self.import_table[idx].arity != sig.params.len() => {
+          return Err(format!(
+            "import {name} registered with {sig}, but takes {} params",
+            self.import_table[idx].arity
+          ));
+        }
+        Some(_) => {}
+        None => return Err(format!("import {name} registered with {sig}, but not provided")),
+      }
+    }
+    Ok(())
+  }
+
+  pub fn set_user_data<T: Any + MaybeSend>(&mut self, data: T) {
+    self.user_data = CalxUserData(Some(Box::new(data)));
+  }
+
+  pub fn user_data<T: Any>(&self) -> Option<&T> {
+    self.user_data.0.as_ref()?.downcast_ref::<T>()
+  }
+
+  pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+    self.user_data.0.as_mut()?.downcast_mut::<T>()
+  }
+
+  /// runs function at `idx` for `CalxImportCtx::call`, arguments and return values are checked like `call`
+  fn call_nested(&mut self, idx: usize, args: &[Calx]) -> Result<Vec<Calx>, CalxError> {
+    self.check_call_args(idx, args)?;
+    if self.nested_calls >= CALX_MAX_NESTED_CALLS {
+      return Err(self.gen_err_kind(
+        CalxErrorKind::CallDepthExceeded,
+        format!("calls from imports nested deeper than {CALX_MAX_NESTED_CALLS}"),
+      ));
+    }
+    let finished = self.finished;
+    let return_value = mem::replace(&mut self.return_value, Calx::Nil);
+    self.nested_calls += 1;
+    let result = match self.engine {
+      CalxEngine::Stack => self.run_stack_nested(idx, args.to_vec()),
+      CalxEngine::Register => self.exec_register(idx, args.to_vec()),
+    };
+    self.nested_calls -= 1;
+    self.return_value = return_value;
+    // `quit` in the callee also finishes the caller
+    self.finished = finished || self.quit_code.is_some();
+    self.check_call_rets(idx, result?)
+  }
+
+  /// calls import at `idx` of import table, types are checked when it has a signature
+  pub(crate) fn call_import(&mut self, idx: usize, args: &Vec<Calx>) -> Result<Calx, CalxError> {
+    let import = match self.import_table.get(idx) {
+      Some(import) => import,
+      None => return Err(CalxError::new_raw(format!("missing imported function #{idx}"))),
+    };
+    let mut ret_type = None;
+    if let Some(sig) = &import.sig {
+      for (i, (t, v)) in sig.params.iter().zip(args).enumerate() {
+        if !v.typed_as(t.to_owned()) {
+          return Err(CalxError::new_raw(format!(
+            "argument {i} of import {} expected {t:?}, got {v}",
+            import.name
+          )));
+        }
+      }
+      ret_type = sig.rets.first().map(|t| (t.to_owned(), import.name.to_owned()));
+    }
+    let v = match import.f.to_owned() {
+      CalxImportFn::Plain(f) => f(args)?,
+      CalxImportFn::Closure(f) => {
+        // a `RefCell` or `Mutex` already borrowed by the same closure would panic or block
+        if self.active_imports.contains(&idx) {
+          let name = &self.import_table[idx].name;
+          return Err(CalxError::new_raw(format!(
+            "import {name} is already running, closures can not be entered again"
+          )));
+        }
+        self.active_imports.push(idx);
+        let result = borrow_cell(&f)(&mut CalxImportCtx { vm: self }, args);
+        self.active_imports.pop();
+        result?
+      }
+    };
+    match ret_type {
+      Some((t, name)) if !v.typed_as(t.to_owned()) => {
+        Err(CalxError::new_raw(format!("import {name} expected to return {t:?}, got {v}")))
+      }
+      _ => Ok(v),
+    }
+  }
+}
@@ -1,9 +1,10 @@
-use std::rc::Rc;
+use crate::rc::Rc;
+use bincode::{Decode, Encode};
 
 use crate::{calx::Calx, syntax::CalxSyntax};
 
 /// learning from WASM but for dynamic data
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq, PartialOrd, Decode, Encode)]
 pub enum CalxInstr {
   /// pop from stack, set value at position
   LocalSet(usize),
@@ -11,7 +12,7 @@ pub enum CalxInstr {
   LocalTee(usize),
   /// get value at position load on stack
   LocalGet(usize),
-  /// increase size of array of locals
+  /// increase size of array of locals, deprecated since `locals_size` of function is allocated at call
   LocalNew,
   /// set global value at position
   GlobalSet(usize),
@@ -87,8 +88,8 @@ pub enum CalxInstr {
   Call(usize),
   /// tail recursion
   ReturnCall(usize),
-  /// call import
-  CallImport(Rc<str>),
+  /// call import by index in import table of VM
+  CallImport(usize),
   /// unreachable panic
   Unreachable,
   /// no operation placeholder
@@ -101,6 +102,81 @@ pub enum CalxInstr {
   Assert(Rc<str>),
   /// inspecting stack
   Inspect,
+  /// (fused) `local.get a; local.get b; i.add`, push sum of two i64 locals
+  IntAddLocals(usize, usize),
+  /// (fused) `local.get a; const k; i.add`, push sum of i64 local and constant
+  IntAddLocalConst(usize, i64),
+  /// (fused) `local.get a; const k; i.add; local.set a`, increase i64 local by constant
+  LocalIncrease(usize, i64),
+  /// (fused) `local.get a; const k; i.lt; jmp-if to`, with any of i64 comparisons
+  JmpIfLocalConst {
+    local: usize,
+    cmp: CalxIntCmp,
+    value: i64,
+    to: usize,
+  },
+}
+
+/// comparison of two i64 numbers, used in fused instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Decode, Encode)]
+pub enum CalxIntCmp {
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+}
+
+impl CalxIntCmp {
+  #[inline(always)]
+  pub fn eval(self, a: i64, b: i64) -> bool {
+    match self {
+      CalxIntCmp::Eq => a == b,
+      CalxIntCmp::Ne => a != b,
+      CalxIntCmp::Lt => a < b,
+      CalxIntCmp::Le => a <= b,
+      CalxIntCmp::Gt => a > b,
+      CalxIntCmp::Ge => a >= b,
+    }
+  }
+
+  /// comparison from instruction like `i.lt`
+  pub fn from_instr(instr: &CalxInstr) -> Option<Self> {
+    match instr {
+      CalxInstr::IntEq => Some(CalxIntCmp::Eq),
+      CalxInstr::IntNe => Some(CalxIntCmp::Ne),
+      CalxInstr::IntLt => Some(CalxIntCmp::Lt),
+      CalxInstr::IntLe => Some(CalxIntCmp::Le),
+      CalxInstr::IntGt => Some(CalxIntCmp::Gt),
+      CalxInstr::IntGe => Some(CalxIntCmp::Ge),
+      _ => None,
+    }
+  }
+
+  /// instruction like `i.lt` for this comparison
+  pub fn to_instr(self) -> CalxInstr {
+    match self {
+      CalxIntCmp::Eq => CalxInstr::IntEq,
+      CalxIntCmp::Ne => CalxInstr::IntNe,
+      CalxIntCmp::Lt => CalxInstr::IntLt,
+      CalxIntCmp::Le => CalxInstr::IntLe,
+      CalxIntCmp::Gt => CalxInstr::IntGt,
+      CalxIntCmp::Ge => CalxInstr::IntGe,
+    }
+  }
+
+  /// comparison that is true exactly when this one is false
+  pub fn inverse(self) -> Self {
+    match self {
+      CalxIntCmp::Eq => CalxIntCmp::Ne,
+      CalxIntCmp::Ne => CalxIntCmp::Eq,
+      CalxIntCmp::Lt => CalxIntCmp::Ge,
+      CalxIntCmp::Le => CalxIntCmp::Gt,
+      CalxIntCmp::Gt => CalxIntCmp::Le,
+      CalxIntCmp::Ge => CalxIntCmp::Lt,
+    }
+  }
 }
 
 impl TryFrom<&CalxSyntax> for CalxInstr {
@@ -153,7 +229,6 @@ impl TryFrom<&CalxSyntax> for CalxInstr {
       CalxSyntax::Quit(a) => Ok(Self::Quit(a.to_owned())),
       CalxSyntax::Return => Ok(Self::Return),
       CalxSyntax::Assert(a) => Ok(Self::Assert(a.to_owned())),
-      CalxSyntax::CallImport(a) => Ok(Self::CallImport(a.to_owned())),
       // debug
       CalxSyntax::Inspect => Ok(Self::Inspect),
 
@@ -163,6 +238,7 @@ impl TryFrom<&CalxSyntax> for CalxInstr {
       CalxSyntax::Block { .. } => Err("Block should be handled manually".to_string()),
       CalxSyntax::BlockEnd(a) => Err(format!("BlockEnd should be handled manually: {a}")),
       CalxSyntax::Call(_) => Err("Call should be handled manually".to_string()),
+      CalxSyntax::CallImport(_) => Err("CallImport should be handled manually".to_string()),
       CalxSyntax::ReturnCall(_) => Err("ReturnCall should be handled manually".to_string()),
       CalxSyntax::If { .. } => Err("If should be handled manually".to_string()),
       CalxSyntax::ThenEnd => Err("ThenEnd should be handled manually".to_string()),
@@ -230,6 +306,59 @@ impl CalxInstr {
       CalxInstr::Assert(_) => (1, 0),
       // debug
       CalxInstr::Inspect => (0, 0),
+      // fused
+      CalxInstr::IntAddLocals(..) => (0, 1),
+      CalxInstr::IntAddLocalConst(..) => (0, 1),
+      CalxInstr::LocalIncrease(..) => (0, 0),
+      CalxInstr::JmpIfLocalConst { .. } => (0, 0),
+    }
+  }
+
+  /// absolute target of a jump instruction at `pointer`, offsets are resolved, might be out of range
+  pub fn jump_target(&self, pointer: usize) -> Option<i64> {
+    match self {
+      CalxInstr::Jmp(line) | CalxInstr::JmpIf(line) | CalxInstr::JmpIfLocalConst { to: line, .. } => Some(*line as i64),
+      CalxInstr::JmpOffset(l) | CalxInstr::JmpOffsetIf(l) => Some(pointer as i64 + *l as i64),
+      _ => None,
+    }
+  }
+
+  /// jumps that might also continue to next instruction
+  pub fn is_conditional_jump(&self) -> bool {
+    matches!(
+      self,
+      CalxInstr::JmpIf(_) | CalxInstr::JmpOffsetIf(_) | CalxInstr::JmpIfLocalConst { .. }
+    )
+  }
+
+  /// jumps that never continue to next instruction
+  pub fn is_unconditional_jump(&self) -> bool {
+    matches!(self, CalxInstr::Jmp(_) | CalxInstr::JmpOffset(_))
+  }
+
+  /// update indexes of locals used by this instruction
+  pub fn map_locals(&mut self, mut f: impl FnMut(usize) -> usize) {
+    match self {
+      CalxInstr::LocalGet(i)
+      | CalxInstr::LocalSet(i)
+      | CalxInstr::LocalTee(i)
+      | CalxInstr::IntAddLocalConst(i, _)
+      | CalxInstr::LocalIncrease(i, _)
+      | CalxInstr::JmpIfLocalConst { local: i, .. } => *i = f(*i),
+      CalxInstr::IntAddLocals(a, b) => {
+        *a = f(*a);
+        *b = f(*b);
+      }
+      _ => {}
+    }
+  }
+
+  /// update target of a jump instruction at `pointer`, offsets are recalculated
+  pub fn set_jump_target(&mut self, pointer: usize, target: usize) {
+    match self {
+      CalxInstr::Jmp(line) | CalxInstr::JmpIf(line) | CalxInstr::JmpIfLocalConst { to: line, .. } => *line = target,
+      CalxInstr::JmpOffset(l) | CalxInstr::JmpOffsetIf(l) => *l = (target as i64 - pointer as i64) as i32,
+      _ => {}
     }
   }
 }
@@ -0,0 +1,77 @@
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc,
+};
+use std::time::{Duration, Instant};
+
+use super::CalxErrorKind;
+
+/// instructions run between two checks of interrupt handle and timeout
+pub const CALX_CHECK_INTERVAL: u32 = 1024;
+
+/// shared flag for stopping a running VM from another thread,
+/// the flag stays set until `clear` is called, so later runs stop too
+#[derive(Debug, Clone, Default)]
+pub struct CalxInterruptHandle {
+  flag: Arc<AtomicBool>,
+}
+
+impl CalxInterruptHandle {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn interrupt(&self) {
+    self.flag.store(true, Ordering::Relaxed)
+  }
+
+  pub fn is_interrupted(&self) -> bool {
+    self.flag.load(Ordering::Relaxed)
+  }
+
+  pub fn clear(&self) {
+    self.flag.store(false, Ordering::Relaxed)
+  }
+}
+
+/// counts down instructions in a run loop, and checks handle and deadline when it reaches zero
+pub(crate) struct CalxInterruptCheck {
+  handle: CalxInterruptHandle,
+  timeout: Option<Duration>,
+  deadline: Option<Instant>,
+  interval: u32,
+  countdown: u32,
+}
+
+impl CalxInterruptCheck {
+  pub fn new(handle: &CalxInterruptHandle, timeout: Option<Duration>, interval: u32) -> Self {
+    let interval = interval.max(1);
+    CalxInterruptCheck {
+      handle: handle.to_owned(),
+      timeout,
+      deadline: timeout.map(|t| Instant::now() + t),
+      interval,
+      countdown: interval,
+    }
+  }
+
+  /// called before each instruction, returns kind and message of error when running should stop
+  #[inline(always)]
+  pub fn tick(&mut self) -> Option<(CalxErrorKind, String)> {
+    self.countdown -= 1;
+    if self.countdown > 0 {
+      return None;
+    }
+    self.countdown = self.interval;
+
+    if self.handle.is_interrupted() {
+      return Some((CalxErrorKind::Interrupted, String::from("interrupted by handle")));
+    }
+    match (self.deadline, self.timeout) {
+      (Some(deadline), Some(timeout)) if Instant::now() >= deadline => {
+        Some((CalxErrorKind::Timeout, format!("timeout after {timeout:?}")))
+      }
+      _ => None,
+    }
+  }
+}
@@ -0,0 +1,58 @@
+use crate::calx::Calx;
+
+use super::{CalxErrorKind, CalxVM};
+
+/// approximate bytes held on heap by strings and lists, from `CalxVM::memory_usage`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CalxMemoryUsage {
+  pub stack: usize,
+  /// locals of all frames
+  pub locals: usize,
+  pub globals: usize,
+}
+
+impl CalxMemoryUsage {
+  pub fn total(&self) -> usize {
+    self.stack + self.locals + self.globals
+  }
+}
+
+/// values are walked again after at least `max_memory / CALX_HEAP_WALK_ROOM` bytes are counted
+const CALX_HEAP_WALK_ROOM: usize = 16;
+
+pub(crate) fn heap_size_of(values: &[Calx]) -> usize {
+  values.iter().map(Calx::heap_size).sum()
+}
+
+impl CalxVM {
+  /// heap bytes held by values on stack, in locals and in globals
+  pub fn memory_usage(&self) -> CalxMemoryUsage {
+    CalxMemoryUsage {
+      stack: heap_size_of(&self.stack),
+      locals: heap_size_of(&self.top_frame.locals) + self.frames.iter().map(|x| heap_size_of(&x.locals)).sum::<usize>(),
+      globals: heap_size_of(&self.globals),
+    }
+  }
+
+  /// adds bytes of a newly created value to a running estimate. values are only walked by `used`
+  /// when the estimate passes `max_memory`, returns error kind and message when usage is really beyond.
+  /// usage staying close to the limit is not walked for every value, it may pass the limit by the room left before reported
+  pub(crate) fn track_heap(&mut self, bytes: usize, used: impl FnOnce(&Self) -> usize) -> Option<(CalxErrorKind, String)> {
+    let limit = self.max_memory?;
+    self.heap_estimate += bytes;
+    if self.heap_estimate <= limit {
+      return None;
+    }
+    let used = used(self);
+    if used > limit {
+      self.heap_estimate = used;
+      Some((
+        CalxErrorKind::MemoryExceeded,
+        format!("memory usage {used} bytes exceeds max_memory {limit}"),
+      ))
+    } else {
+      self.heap_estimate = used.min(limit - limit / CALX_HEAP_WALK_ROOM);
+      None
+    }
+  }
+}
@@ -0,0 +1,39 @@
+use crate::calx::Calx;
+
+use super::{func::CalxFunc, import::CalxImportDecl, register::CalxEngine, CalxImportsDict, CalxVM};
+
+/// preprocessed functions with imports, for creating VMs without parsing and preprocessing again.
+/// cloning is cheap since instructions are shared, with feature `sync` it can be sent to other threads
+#[derive(Debug, Clone)]
+pub struct CalxProgram {
+  pub funcs: Vec<CalxFunc>,
+  pub imports: CalxImportsDict,
+  pub import_decls: Vec<CalxImportDecl>,
+}
+
+impl CalxProgram {
+  /// preprocesses functions, `main` is not required
+  pub fn new(funcs: Vec<CalxFunc>, imports: CalxImportsDict) -> Result<Self, String> {
+    Self::new_with_decls(funcs, imports, vec![])
+  }
+
+  /// like `new`, with `import` declarations from source
+  pub fn new_with_decls(funcs: Vec<CalxFunc>, imports: CalxImportsDict, import_decls: Vec<CalxImportDecl>) -> Result<Self, String> {
+    let mut vm = CalxVM::new(funcs, vec![], imports);
+    vm.import_decls = import_decls;
+    vm.preprocess(false)?;
+    Ok(CalxProgram {
+      funcs: vm.funcs,
+      imports: vm.imports,
+      import_decls: vm.import_decls,
+    })
+  }
+
+  /// a VM with its own stack and globals, sharing instructions with the program
+  pub fn vm(&self, globals: Vec<Calx>, engine: CalxEngine) -> CalxVM {
+    let mut vm = CalxVM::new_with_engine(self.funcs.to_owned(), globals, self.imports.to_owned(), engine);
+    vm.import_decls = self.import_decls.to_owned();
+    vm.rebuild_import_table();
+    vm
+  }
+}
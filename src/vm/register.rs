@@ -0,0 +1,911 @@
+/*! Register engine, an alternative to the stack interpreter.
+ *
+ * Verified instructions are lowered into three-address instructions working on slots of a frame.
+ * Locals take the first slots, and each stack depth of the function gets a slot after locals,
+ * so values are no longer pushed and popped. Values read from locals are used in place
+ * until locals change, and results written right before `local.set` go into locals directly.
+ */
+
+use crate::rc::Rc;
+use bincode::{Decode, Encode};
+use std::mem;
+
+use crate::calx::Calx;
+use crate::optimize::jump_targets;
+
+use super::{
+  frame::CalxFrame,
+  func::CalxFunc,
+  import::CalxImport,
+  instr::{CalxInstr, CalxIntCmp},
+  memory::heap_size_of,
+  verifier::{globals_limit, verify_func},
+  CalxError, CalxErrorKind, CalxVM,
+};
+
+/// engine for running instructions, chosen when creating `CalxVM`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Decode, Encode)]
+pub enum CalxEngine {
+  /// interprets `CalxInstr` with a stack of values
+  #[default]
+  Stack,
+  /// lowers `CalxInstr` into `CalxRegInstr` before running, a run completes in one go.
+  /// fuel and stepping are not supported, and snapshots can only be taken before or after a run
+  Register,
+}
+
+/// operations with two operands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub enum CalxRegBinary {
+  IntAdd,
+  IntMul,
+  IntDiv,
+  IntRem,
+  IntShr,
+  IntShl,
+  IntEq,
+  IntNe,
+  IntLt,
+  IntLe,
+  IntGt,
+  IntGe,
+  Add,
+  Mul,
+  Div,
+}
+
+impl CalxRegBinary {
+  fn from_instr(instr: &CalxInstr) -> Option<Self> {
+    use CalxRegBinary::*;
+    match instr {
+      CalxInstr::IntAdd => Some(IntAdd),
+      CalxInstr::IntMul => Some(IntMul),
+      CalxInstr::IntDiv => Some(IntDiv),
+      CalxInstr::IntRem => Some(IntRem),
+      CalxInstr::IntShr => Some(IntShr),
+      CalxInstr::IntShl => Some(IntShl),
+      CalxInstr::IntEq => Some(IntEq),
+      CalxInstr::IntNe => Some(IntNe),
+      CalxInstr::IntLt => Some(IntLt),
+      CalxInstr::IntLe => Some(IntLe),
+      CalxInstr::IntGt => Some(IntGt),
+      CalxInstr::IntGe => Some(IntGe),
+      CalxInstr::Add => Some(Add),
+      CalxInstr::Mul => Some(Mul),
+      CalxInstr::Div => Some(Div),
+      _ => None,
+    }
+  }
+
+  /// same results and messages as the stack engine
+  #[inline(always)]
+  fn eval(self, a: &Calx, b: &Calx) -> Result<Calx, String> {
+    use CalxRegBinary::*;
+    match (self, a, b) {
+      (IntAdd | Add, Calx::I64(n1), Calx::I64(n2)) => n1
+        .checked_add(*n2)
+        .map(Calx::I64)
+        .ok_or_else(|| format!("integer overflow when adding {n1} and {n2}")),
+      (IntMul | Mul, Calx::I64(n1), Calx::I64(n2)) => n1
+        .checked_mul(*n2)
+        .map(Calx::I64)
+        .ok_or_else(|| format!("integer overflow when multiplying {n1} and {n2}")),
+      (IntDiv, Calx::I64(n1), Calx::I64(n2)) => n1
+        .checked_div(*n2)
+        .map(Calx::I64)
+        .ok_or_else(|| format!("failed to divide {n1} by {n2}")),
+      (IntRem, Calx::I64(n1), Calx::I64(n2)) => n1
+        .checked_rem(*n2)
+        .map(Calx::I64)
+        .ok_or_else(|| format!("failed to get remainder of {n1} by {n2}")),
+      (IntShr, Calx::I64(n), Calx::I64(bits)) => n
+        .checked_shr(*bits as u32)
+        .map(Calx::I64)
+        .ok_or_else(|| format!("invalid number for SHR, {a:?} {b:?}")),
+      (IntShl, Calx::I64(n), Calx::I64(bits)) => n
+        .checked_shl(*bits as u32)
+        .map(Calx::I64)
+        .ok_or_else(|| format!("invalid number for SHL, {a:?} {b:?}")),
+      (IntEq, Calx::I64(n1), Calx::I64(n2)) => Ok(Calx::Bool(n1 == n2)),
+      (IntNe, Calx::I64(n1), Calx::I64(n2)) => Ok(Calx::Bool(n1 != n2)),
+      (IntLt, Calx::I64(n1), Calx::I64(n2)) => Ok(Calx::Bool(n1 < n2)),
+      (IntLe, Calx::I64(n1), Calx::I64(n2)) => Ok(Calx::Bool(n1 <= n2)),
+      (IntGt, Calx::I64(n1), Calx::I64(n2)) => Ok(Calx::Bool(n1 > n2)),
+      (IntGe, Calx::I64(n1), Calx::I64(n2)) => Ok(Calx::Bool(n1 >= n2)),
+      (Add, Calx::F64(n1), Calx::F64(n2)) => Ok(Calx::F64(n1 + n2)),
+      (Mul, Calx::F64(n1), Calx::F64(n2)) => Ok(Calx::F64(n1 * n2)),
+      (Div, Calx::F64(n1), Calx::F64(n2)) => Ok(Calx::F64(n1 / n2)),
+      (op, _, _) => Err(match op {
+        IntAdd => format!("expected 2 integers to add, {a:?} {b:?}"),
+        IntMul => format!("expected 2 integers to multiply, {a:?} {b:?}"),
+        IntDiv => format!("expected 2 integers to divide, {a:?} {b:?}"),
+        IntRem => format!("expected 2 integers for remainder, {a:?} {b:?}"),
+        IntShr => format!("invalid number for SHR, {a:?} {b:?}"),
+        IntShl => format!("invalid number for SHL, {a:?} {b:?}"),
+        IntEq => format!("expected 2 integers to eq compare, {a:?} {b:?}"),
+        IntNe => format!("expected 2 integers to ne compare, {a:?} {b:?}"),
+        IntLt => format!("expected 2 integers to lt compare, {a:?} {b:?}"),
+        IntLe => format!("expected 2 integers to le compare, {a:?} {b:?}"),
+        IntGt => format!("expected 2 integers to gt compare, {a:?} {b:?}"),
+        IntGe => format!("expected 2 integers to ge compare, {a:?} {b:?}"),
+        Add => format!("expected 2 numbers to +, {a:?} {b:?}"),
+        Mul => format!("expected 2 numbers to multiply, {a:?} {b:?}"),
+        Div => format!("expected 2 numbers to divide, {a:?} {b:?}"),
+      }),
+    }
+  }
+}
+
+/// three-address instruction, numbers are slots in current frame
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum CalxRegInstr {
+  Const {
+    dst: usize,
+    value: Calx,
+  },
+  Copy {
+    dst: usize,
+    src: usize,
+  },
+  Binary {
+    op: CalxRegBinary,
+    dst: usize,
+    lhs: usize,
+    rhs: usize,
+  },
+  IntNeg {
+    dst: usize,
+    src: usize,
+  },
+  Neg {
+    dst: usize,
+    src: usize,
+  },
+  /// i64 in slot plus a constant
+  IntAddConst {
+    dst: usize,
+    src: usize,
+    value: i64,
+  },
+  GlobalGet {
+    dst: usize,
+    idx: usize,
+  },
+  GlobalSet {
+    idx: usize,
+    src: usize,
+  },
+  GlobalNew,
+  Jmp(usize),
+  /// jump if value in slot is true
+  JmpIf {
+    cond: usize,
+    to: usize,
+  },
+  /// jump if comparing i64 in slot with a constant is true
+  JmpIfCmp {
+    src: usize,
+    cmp: CalxIntCmp,
+    value: i64,
+    to: usize,
+  },
+  /// params are taken from slots starting at `args`, return values are placed from `args`
+  Call {
+    func: usize,
+    args: usize,
+  },
+  ReturnCall {
+    func: usize,
+    args: usize,
+  },
+  /// return value is placed at `args`, or dropped when import returns nothing
+  CallImport {
+    import: usize,
+    args: usize,
+    size: usize,
+    ret_size: usize,
+  },
+  /// return values are in slots starting at `src`
+  Return {
+    src: usize,
+  },
+  Echo(usize),
+  Assert {
+    src: usize,
+    message: Rc<str>,
+  },
+  /// `depth` is the number of slots used as stack
+  Inspect {
+    depth: usize,
+  },
+  Quit(usize),
+  Unreachable,
+}
+
+/// function lowered for register engine
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct CalxRegFunc {
+  pub instrs: Vec<CalxRegInstr>,
+  /// position in `CalxFunc::instrs` of each instruction, for error messages
+  pub pointers: Vec<usize>,
+  pub params_size: usize,
+  pub ret_size: usize,
+  pub locals_size: usize,
+  /// locals, and slots for values on stack
+  pub slots_size: usize,
+}
+
+/// lowers a function after `preprocess`, instructions are verified first.
+/// locals are allocated upfront, so `local.new` does nothing here.
+/// `globals_limit` is checked like in `verify_func`, globals are still checked at runtime.
+pub fn lower_func(idx: usize, funcs: &[CalxFunc], globals_limit: usize, imports: &[CalxImport]) -> Result<CalxRegFunc, String> {
+  let depths =
+    verify_func(idx, funcs, globals_limit, imports).map_err(|es| es.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"))?;
+  let f = &funcs[idx];
+  let size = f.instrs.len();
+  let params_size = f.params_types.len();
+  let ret_size = f.ret_types.len();
+
+  let locals_size = f.count_locals();
+  let max_depth = depths.iter().flatten().max().copied().unwrap_or(0);
+
+  let targets = jump_targets(&f.instrs);
+  let mut l = Lowering {
+    locals_size,
+    stack: vec![],
+    instrs: vec![],
+    pointers: vec![],
+    pointer: 0,
+    block_start: 0,
+  };
+  // new position for each old position, and old targets of jumps are replaced after all positions are known
+  let mut positions: Vec<usize> = Vec::with_capacity(size + 1);
+  let mut fallthrough = true;
+
+  for (pointer, depth) in depths.iter().enumerate() {
+    l.pointer = pointer;
+    if targets[pointer] && fallthrough {
+      l.materialize(0);
+    }
+    positions.push(l.instrs.len());
+    let depth = match depth {
+      Some(d) => *d,
+      None => {
+        fallthrough = false;
+        continue;
+      }
+    };
+    if targets[pointer] || !fallthrough {
+      l.stack = vec![Operand::Slot; depth];
+      l.block_start = l.instrs.len();
+    }
+    fallthrough = true;
+
+    if pointer == size {
+      l.materialize(0);
+      let src = l.slot(depth - ret_size);
+      l.emit(CalxRegInstr::Return { src });
+      break;
+    }
+
+    let instr = &f.instrs[pointer];
+    match instr {
+      CalxInstr::LocalGet(i) => l.stack.push(Operand::Local(*i)),
+      CalxInstr::LocalSet(i) => {
+        l.materialize_local(*i);
+        let top = l.stack.len() - 1;
+        let forwarded = match (l.stack[top], l.instrs.len() > l.block_start) {
+          (Operand::Slot, true) => {
+            let slot = l.slot(top);
+            match l.instrs.last_mut().and_then(dst_of) {
+              Some(dst) if *dst == slot => {
+                *dst = *i;
+                true
+              }
+              _ => false,
+            }
+          }
+          _ => false,
+        };
+        let src = l.pop_operand();
+        if !forwarded && src != *i {
+          l.emit(CalxRegInstr::Copy { dst: *i, src });
+        }
+      }
+      CalxInstr::LocalTee(i) => {
+        l.materialize_local(*i);
+        let src = l.operand(l.stack.len() - 1);
+        if src != *i {
+          l.emit(CalxRegInstr::Copy { dst: *i, src });
+        }
+      }
+      CalxInstr::LocalNew | CalxInstr::Nop => {}
+      CalxInstr::GlobalGet(idx) => {
+        let dst = l.push_slot();
+        l.emit(CalxRegInstr::GlobalGet { dst, idx: *idx });
+      }
+      CalxInstr::GlobalSet(idx) => {
+        let src = l.pop_operand();
+        l.emit(CalxRegInstr::GlobalSet { idx: *idx, src });
+      }
+      CalxInstr::GlobalNew => l.emit(CalxRegInstr::GlobalNew),
+      CalxInstr::Const(value) => {
+        let dst = l.push_slot();
+        l.emit(CalxRegInstr::Const {
+          dst,
+          value: value.to_owned(),
+        });
+      }
+      CalxInstr::Dup => match l.stack[l.stack.len() - 1] {
+        Operand::Local(i) => l.stack.push(Operand::Local(i)),
+        Operand::Slot => {
+          let src = l.slot(l.stack.len() - 1);
+          let dst = l.push_slot();
+          l.emit(CalxRegInstr::Copy { dst, src });
+        }
+      },
+      CalxInstr::Drop => {
+        l.stack.pop();
+      }
+      CalxInstr::IntNeg | CalxInstr::Neg => {
+        let src = l.pop_operand();
+        let dst = l.push_slot();
+        l.emit(match instr {
+          CalxInstr::IntNeg => CalxRegInstr::IntNeg { dst, src },
+          _ => CalxRegInstr::Neg { dst, src },
+        });
+      }
+      CalxInstr::IntAddLocals(a, b) => {
+        let dst = l.push_slot();
+        l.emit(CalxRegInstr::Binary {
+          op: CalxRegBinary::IntAdd,
+          dst,
+          lhs: *a,
+          rhs: *b,
+        });
+      }
+      CalxInstr::IntAddLocalConst(a, value) => {
+        let dst = l.push_slot();
+        l.emit(CalxRegInstr::IntAddConst {
+          dst,
+          src: *a,
+          value: *value,
+        });
+      }
+      CalxInstr::LocalIncrease(a, value) => {
+        l.materialize_local(*a);
+        l.emit(CalxRegInstr::IntAddConst {
+          dst: *a,
+          src: *a,
+          value: *value,
+        });
+      }
+      CalxInstr::JmpIfLocalConst { local, cmp, value, to } => {
+        l.materialize(0);
+        l.emit(CalxRegInstr::JmpIfCmp {
+          src: *local,
+          cmp: *cmp,
+          value: *value,
+          to: *to,
+        });
+      }
+      CalxInstr::Jmp(_) | CalxInstr::JmpOffset(_) => {
+        l.materialize(0);
+        let to = instr.jump_target(pointer).expect("jump target") as usize;
+        l.emit(CalxRegInstr::Jmp(to));
+        fallthrough = false;
+      }
+      CalxInstr::JmpIf(_) | CalxInstr::JmpOffsetIf(_) => {
+        let cond = l.pop_operand();
+        l.materialize(0);
+        let to = instr.jump_target(pointer).expect("jump target") as usize;
+        l.emit(CalxRegInstr::JmpIf { cond, to });
+      }
+      CalxInstr::Call(func) | CalxInstr::ReturnCall(func) => {
+        let n = funcs[*func].params_types.len();
+        let args = l.take_args(n);
+        if let CalxInstr::Call(_) = instr {
+          l.emit(CalxRegInstr::Call { func: *func, args });
+          for _ in 0..funcs[*func].ret_types.len() {
+            l.push_slot();
+          }
+        } else {
+          l.emit(CalxRegInstr::ReturnCall { func: *func, args });
+          fallthrough = false;
+        }
+      }
+      CalxInstr::CallImport(import) => {
+        let size = imports[*import].arity;
+        let ret_size = imports[*import].ret_size();
+        let args = l.take_args(size);
+        l.emit(CalxRegInstr::CallImport {
+          import: *import,
+          args,
+          size,
+          ret_size,
+        });
+        if ret_size > 0 {
+          l.push_slot();
+        }
+      }
+      CalxInstr::Return => {
+        let src = l.take_args(ret_size);
+        l.emit(CalxRegInstr::Return { src });
+        fallthrough = false;
+      }
+      CalxInstr::Echo => {
+        let src = l.pop_operand();
+        l.emit(CalxRegInstr::Echo(src));
+      }
+      CalxInstr::Assert(message) => {
+        let src = l.pop_operand();
+        l.emit(CalxRegInstr::Assert {
+          src,
+          message: message.to_owned(),
+        });
+      }
+      CalxInstr::Inspect => {
+        l.materialize(0);
+        l.emit(CalxRegInstr::Inspect { depth: l.stack.len() });
+      }
+      CalxInstr::Quit(code) => {
+        l.emit(CalxRegInstr::Quit(*code));
+        fallthrough = false;
+      }
+      CalxInstr::Unreachable => {
+        l.emit(CalxRegInstr::Unreachable);
+        fallthrough = false;
+      }
+      a => match CalxRegBinary::from_instr(a) {
+        Some(op) => {
+          let rhs = l.pop_operand();
+          let lhs = l.pop_operand();
+          let dst = l.push_slot();
+          l.emit(CalxRegInstr::Binary { op, dst, lhs, rhs });
+        }
+        None => return Err(format!("{a:?} is not supported by register engine")),
+      },
+    }
+  }
+
+  let mut instrs = l.instrs;
+  for instr in &mut instrs {
+    match instr {
+      CalxRegInstr::Jmp(to) | CalxRegInstr::JmpIf { to, .. } | CalxRegInstr::JmpIfCmp { to, .. } => *to = positions[*to],
+      _ => {}
+    }
+  }
+
+  Ok(CalxRegFunc {
+    instrs,
+    pointers: l.pointers,
+    params_size,
+    ret_size,
+    locals_size,
+    slots_size: locals_size + max_depth,
+  })
+}
+
+/// value on stack during lowering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+  /// in the slot for its depth
+  Slot,
+  /// not copied from local yet
+  Local(usize),
+}
+
+struct Lowering {
+  locals_size: usize,
+  stack: Vec<Operand>,
+  instrs: Vec<CalxRegInstr>,
+  pointers: Vec<usize>,
+  /// position of the instruction being lowered
+  pointer: usize,
+  /// instructions before are not in current block, their results are not moved
+  block_start: usize,
+}
+
+impl Lowering {
+  fn slot(&self, depth: usize) -> usize {
+    self.locals_size + depth
+  }
+
+  fn emit(&mut self, instr: CalxRegInstr) {
+    self.instrs.push(instr);
+    self.pointers.push(self.pointer);
+  }
+
+  /// copies locals into slots for values from `from` to the top
+  fn materialize(&mut self, from: usize) {
+    for depth in from..self.stack.len() {
+      if let Operand::Local(src) = self.stack[depth] {
+        let dst = self.slot(depth);
+        self.emit(CalxRegInstr::Copy { dst, src });
+        self.stack[depth] = Operand::Slot;
+      }
+    }
+  }
+
+  /// copies values of a local before it changes
+  fn materialize_local(&mut self, local: usize) {
+    for depth in 0..self.stack.len() {
+      if self.stack[depth] == Operand::Local(local) {
+        let dst = self.slot(depth);
+        self.emit(CalxRegInstr::Copy { dst, src: local });
+        self.stack[depth] = Operand::Slot;
+      }
+    }
+  }
+
+  fn operand(&self, depth: usize) -> usize {
+    match self.stack[depth] {
+      Operand::Slot => self.slot(depth),
+      Operand::Local(i) => i,
+    }
+  }
+
+  fn pop_operand(&mut self) -> usize {
+    let src = self.operand(self.stack.len() - 1);
+    self.stack.pop();
+    src
+  }
+
+  fn push_slot(&mut self) -> usize {
+    self.stack.push(Operand::Slot);
+    self.slot(self.stack.len() - 1)
+  }
+
+  /// moves top `n` values into their slots and pops them, returns the first slot
+  fn take_args(&mut self, n: usize) -> usize {
+    let from = self.stack.len() - n;
+    self.materialize(from);
+    self.stack.truncate(from);
+    self.slot(from)
+  }
+}
+
+/// slot written by instructions that only compute a value
+fn dst_of(instr: &mut CalxRegInstr) -> Option<&mut usize> {
+  match instr {
+    CalxRegInstr::Const { dst, .. }
+    | CalxRegInstr::Copy { dst, .. }
+    | CalxRegInstr::Binary { dst, .. }
+    | CalxRegInstr::IntNeg { dst, .. }
+    | CalxRegInstr::Neg { dst, .. }
+    | CalxRegInstr::IntAddConst { dst, .. }
+    | CalxRegInstr::GlobalGet { dst, .. } => Some(dst),
+    _ => None,
+  }
+}
+
+/// functions lowered by register engine, reused by runs and calls until functions or imports change
+#[derive(Debug, Clone)]
+pub(crate) struct CalxRegCache {
+  /// instructions lowered from, `funcs` may be changed directly since it's public
+  instrs: Vec<Rc<Vec<CalxInstr>>>,
+  code: Rc<Vec<CalxRegFunc>>,
+}
+
+/// frame of a caller, waiting for callee to return
+struct RegFrame {
+  func: usize,
+  base: usize,
+  pointer: usize,
+  /// where return values of callee go
+  ret_to: usize,
+}
+
+impl CalxVM {
+  /// lowers functions and runs function at `entry` with register engine, returns values of `ret_types`
+  pub(crate) fn run_register(&mut self, entry: usize, args: Vec<Calx>) -> Result<Vec<Calx>, CalxError> {
+    self.reset();
+    self.exec_register(entry, args)
+  }
+
+  /// runs function at `entry` with registers of its own, state of VM is not reset, so it's also used for calls from imports
+  pub(crate) fn exec_register(&mut self, entry: usize, args: Vec<Calx>) -> Result<Vec<Calx>, CalxError> {
+    let code = self.lowered_funcs()?;
+    let mut func = entry;
+    let mut f = &code[func];
+    let mut regs: Vec<Calx> = vec![Calx::Nil; f.slots_size];
+    // args beyond locals are not reachable
+    for (i, v) in args.into_iter().take(f.locals_size).enumerate() {
+      regs[i] = v;
+    }
+    let mut frames: Vec<RegFrame> = vec![];
+    let mut base = 0;
+    let mut pointer = 0;
+    let mut check = self.interrupt_check();
+
+    loop {
+      if let Some((kind, message)) = check.tick() {
+        return Err(self.gen_reg_err_kind(f, func, pointer, &regs, base, kind, message));
+      }
+      let instr = match f.instrs.get(pointer) {
+        Some(x) => x,
+        None => return Err(self.gen_reg_err(f, func, pointer, &regs, base, "pointer out of function".to_owned())),
+      };
+
+      match instr {
+        // shares value held by instruction, no new heap to count
+        CalxRegInstr::Const { dst, value } => regs[base + dst] = value.to_owned(),
+        CalxRegInstr::Copy { dst, src } => {
+          regs[base + dst] = regs[base + src].to_owned();
+          self.check_reg_heap(f, func, pointer, &regs, base, base + dst)?;
+        }
+        CalxRegInstr::Binary { op, dst, lhs, rhs } => match op.eval(&regs[base + lhs], &regs[base + rhs]) {
+          Ok(v) => regs[base + dst] = v,
+          Err(message) => return Err(self.gen_reg_err(f, func, pointer, &regs, base, message)),
+        },
+        CalxRegInstr::IntNeg { dst, src } => match regs[base + src] {
+          Calx::I64(n) => match n.checked_neg() {
+            Some(v) => regs[base + dst] = Calx::I64(v),
+            None => {
+              let message = format!("integer overflow when negating {n}");
+              return Err(self.gen_reg_err(f, func, pointer, &regs, base, message));
+            }
+          },
+          ref v => {
+            let message = format!("expected int, got {v}");
+            return Err(self.gen_reg_err(f, func, pointer, &regs, base, message));
+          }
+        },
+        CalxRegInstr::Neg { dst, src } => match regs[base + src] {
+          Calx::F64(n) => regs[base + dst] = Calx::F64(-n),
+          ref v => {
+            let message = format!("expected float, got {v}");
+            return Err(self.gen_reg_err(f, func, pointer, &regs, base, message));
+          }
+        },
+        CalxRegInstr::IntAddConst { dst, src, value } => match regs[base + src] {
+          Calx::I64(n) => match n.checked_add(*value) {
+            Some(v) => regs[base + dst] = Calx::I64(v),
+            None => {
+              let message = format!("integer overflow when adding {n} and {value}");
+              return Err(self.gen_reg_err(f, func, pointer, &regs, base, message));
+            }
+          },
+          ref v => {
+            let message = format!("expected 2 integers to add, {v:?} {value:?}");
+            return Err(self.gen_reg_err(f, func, pointer, &regs, base, message));
+          }
+        },
+        CalxRegInstr::GlobalGet { dst, idx } => match self.globals.get(*idx) {
+          Some(v) => {
+            regs[base + dst] = v.to_owned();
+            self.check_reg_heap(f, func, pointer, &regs, base, base + dst)?;
+          }
+          None => return Err(self.gen_reg_err(f, func, pointer, &regs, base, format!("out of bound in global.get {idx}"))),
+        },
+        CalxRegInstr::GlobalSet { idx, src } => {
+          if *idx >= self.globals.len() {
+            return Err(self.gen_reg_err(f, func, pointer, &regs, base, format!("out of bound in global.set {idx}")));
+          }
+          self.globals[*idx] = regs[base + src].to_owned();
+          self.check_reg_heap(f, func, pointer, &regs, base, base + src)?;
+        }
+        CalxRegInstr::GlobalNew => self.globals.push(Calx::Nil),
+        CalxRegInstr::Jmp(to) => {
+          pointer = *to;
+          continue;
+        }
+        CalxRegInstr::JmpIf { cond, to } => {
+          if matches!(regs[base + cond], Calx::Bool(true) | Calx::I64(1)) {
+            pointer = *to;
+            continue;
+          }
+        }
+        CalxRegInstr::JmpIfCmp { src, cmp, value, to } => match regs[base + src] {
+          Calx::I64(n) => {
+            if cmp.eval(n, *value) {
+              pointer = *to;
+              continue;
+            }
+          }
+          ref v => {
+            let message = format!("expected 2 integers to {cmp:?} compare, {v:?} {value:?}");
+            return Err(self.gen_reg_err(f, func, pointer, &regs, base, message));
+          }
+        },
+        CalxRegInstr::Call { func: callee, args } => {
+          let next = &code[*callee];
+          let next_base = regs.len();
+          let limit = if frames.len() + 1 >= self.max_call_depth {
+            Some((
+              CalxErrorKind::CallDepthExceeded,
+              format!(
+                "call depth exceeds max_call_depth {} when calling {}",
+                self.max_call_depth, self.funcs[*callee].name
+              ),
+            ))
+          } else if next_base + next.slots_size > self.max_stack_len {
+            Some((
+              CalxErrorKind::StackOverflow,
+              format!(
+                "stack length {} exceeds max_stack_len {}",
+                next_base + next.slots_size,
+                self.max_stack_len
+              ),
+            ))
+          } else {
+            None
+          };
+          if let Some((kind, message)) = limit {
+            let mut e = self.gen_reg_err_kind(f, func, pointer, &regs, base, kind, message);
+            e.backtrace.extend(frames.iter().rev().map(|x| self.funcs[x.func].name.to_owned()));
+            return Err(e);
+          }
+          regs.resize(next_base + next.slots_size, Calx::Nil);
+          for k in 0..next.params_size {
+            regs[next_base + k] = mem::replace(&mut regs[base + args + k], Calx::Nil);
+          }
+          frames.push(RegFrame {
+            func,
+            base,
+            pointer,
+            ret_to: base + args,
+          });
+          func = *callee;
+          f = next;
+          base = next_base;
+          pointer = 0;
+          continue;
+        }
+        CalxRegInstr::ReturnCall { func: callee, args } => {
+          let next = &code[*callee];
+          // slots of params are always before slots of args
+          for k in 0..next.params_size {
+            regs[base + k] = mem::replace(&mut regs[base + args + k], Calx::Nil);
+          }
+          regs.truncate(base + next.params_size);
+          regs.resize(base + next.slots_size, Calx::Nil);
+          func = *callee;
+          f = next;
+          pointer = 0;
+          continue;
+        }
+        CalxRegInstr::CallImport {
+          import,
+          args,
+          size,
+          ret_size,
+        } => {
+          let xs = regs[base + args..base + args + size].to_vec();
+          let result = self.call_import(*import, &xs);
+          // `quit` in a function called from the import
+          if self.quit_code.is_some() {
+            return Ok(vec![]);
+          }
+          let v = result?;
+          if *ret_size > 0 {
+            regs[base + args] = v;
+            self.check_reg_heap(f, func, pointer, &regs, base, base + args)?;
+          }
+        }
+        CalxRegInstr::Return { src } => match frames.pop() {
+          Some(parent) => {
+            for k in 0..f.ret_size {
+              regs[parent.ret_to + k] = mem::replace(&mut regs[base + src + k], Calx::Nil);
+            }
+            regs.truncate(base);
+            func = parent.func;
+            f = &code[func];
+            base = parent.base;
+            pointer = parent.pointer;
+          }
+          None => {
+            let values = regs[base + src..base + src + f.ret_size].to_vec();
+            self.make_return(values.last().cloned().unwrap_or(Calx::Nil));
+            return Ok(values);
+          }
+        },
+        CalxRegInstr::Echo(src) => println!("{}", regs[base + src]),
+        CalxRegInstr::Assert { src, message } => {
+          if !matches!(regs[base + src], Calx::Bool(true) | Calx::I64(1)) {
+            return Err(self.gen_reg_err(f, func, pointer, &regs, base, format!("Failed assertion: {message}")));
+          }
+        }
+        CalxRegInstr::Inspect { depth } => {
+          let names: Vec<&str> = frames.iter().map(|x| &*self.funcs[x.func].name).collect();
+          let stack_start = base + f.locals_size;
+          println!("[ ----------------");
+          println!("  Internal frames: {names:?}");
+          println!("  Top frame: {}", self.funcs[func].name);
+          println!("  Locals: {:?}", &regs[base..stack_start]);
+          println!("  Stack({depth}): {:?}", &regs[stack_start..stack_start + depth]);
+          // frames do not share a stack
+          println!("  Sizes: 0 + {}", f.ret_size);
+          println!("  Pointer: {}", f.pointers[pointer]);
+          println!("  -------------- ]");
+        }
+        CalxRegInstr::Quit(code) => {
+          self.quit_code = Some(*code);
+          return Ok(vec![]);
+        }
+        CalxRegInstr::Unreachable => {
+          let message = String::from("reached unreachable");
+          return Err(self.gen_reg_err_kind(f, func, pointer, &regs, base, CalxErrorKind::Unreachable, message));
+        }
+      }
+
+      pointer += 1;
+    }
+  }
+
+  /// lowered functions from cache, lowering again when `funcs` no longer holds the instructions lowered from
+  fn lowered_funcs(&mut self) -> Result<Rc<Vec<CalxRegFunc>>, CalxError> {
+    if let Some(cache) = &self.reg_cache {
+      if cache.instrs.len() == self.funcs.len() && cache.instrs.iter().zip(&self.funcs).all(|(a, f)| Rc::ptr_eq(a, &f.instrs)) {
+        return Ok(cache.code.to_owned());
+      }
+    }
+    let globals_limit = globals_limit(&self.funcs, self.globals.len());
+    let mut code: Vec<CalxRegFunc> = Vec::with_capacity(self.funcs.len());
+    for idx in 0..self.funcs.len() {
+      code.push(lower_func(idx, &self.funcs, globals_limit, &self.import_table).map_err(CalxError::new_raw)?);
+    }
+    let code = Rc::new(code);
+    self.reg_cache = Some(CalxRegCache {
+      instrs: self.funcs.iter().map(|f| f.instrs.to_owned()).collect(),
+      code: code.to_owned(),
+    });
+    Ok(code)
+  }
+
+  /// counts value in `slot` against `max_memory`, slots of all frames are walked when needed
+  #[inline(always)]
+  fn check_reg_heap(
+    &mut self,
+    f: &CalxRegFunc,
+    func: usize,
+    pointer: usize,
+    regs: &[Calx],
+    base: usize,
+    slot: usize,
+  ) -> Result<(), CalxError> {
+    if self.max_memory.is_none() {
+      return Ok(());
+    }
+    match self.track_heap(regs[slot].heap_size(), |vm| heap_size_of(regs) + heap_size_of(&vm.globals)) {
+      Some((kind, message)) => Err(self.gen_reg_err_kind(f, func, pointer, regs, base, kind, message)),
+      None => Ok(()),
+    }
+  }
+
+  fn gen_reg_err(&self, f: &CalxRegFunc, func: usize, pointer: usize, regs: &[Calx], base: usize, message: String) -> CalxError {
+    self.gen_reg_err_kind(f, func, pointer, regs, base, CalxErrorKind::Runtime, message)
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  fn gen_reg_err_kind(
+    &self,
+    f: &CalxRegFunc,
+    func: usize,
+    pointer: usize,
+    regs: &[Calx],
+    base: usize,
+    kind: CalxErrorKind,
+    message: String,
+  ) -> CalxError {
+    let origin = &self.funcs[func];
+    let origin_pointer = f.pointers.get(pointer).copied().unwrap_or(origin.instrs.len());
+    let stack_start = (base + f.locals_size).min(regs.len());
+    CalxError {
+      kind,
+      message,
+      stack: regs[stack_start..].to_vec(),
+      top_frame: CalxFrame {
+        name: origin.name.to_owned(),
+        locals: regs[base.min(stack_start)..stack_start].to_vec(),
+        instrs: origin.instrs.to_owned(),
+        pointer: origin_pointer,
+        initial_stack_size: 0,
+        ret_types: origin.ret_types.to_owned(),
+      },
+      globals: self.globals.to_owned(),
+      syntax_index: Some(origin.syntax_index(origin_pointer)),
+      backtrace: vec![origin.name.to_owned()],
+    }
+  }
+}
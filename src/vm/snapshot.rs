@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use bincode::{Decode, Encode};
+
+use crate::calx::Calx;
+use crate::rc::Rc;
+
+use super::{
+  frame::CalxFrame,
+  fuel::CalxFuelCosts,
+  func::CalxFunc,
+  import::{import_table, placeholder_closure, CalxImportDecl, CalxImportFn, CalxImportSig},
+  instr::CALX_INSTR_EDITION,
+  interrupt::CalxInterruptHandle,
+  register::CalxEngine,
+  verifier::{globals_limit, locals_limit, verify_func},
+  CalxImportsDict, CalxVM,
+};
+
+/// frame refers to its function by index, instructions are taken from functions when restoring
+#[derive(Debug, Clone, PartialEq, Decode, Encode)]
+struct CalxFrameSnapshot {
+  /// `None` for the placeholder frame of a VM without `main`
+  func: Option<usize>,
+  locals: Vec<Calx>,
+  pointer: usize,
+  initial_stack_size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Decode, Encode)]
+struct CalxSnapshot {
+  edition: String,
+  engine: CalxEngine,
+  funcs: Vec<CalxFunc>,
+  /// names of imports in order of `import_table`, since instructions refer to imports by index
+  imports: Vec<String>,
+  /// names and arities of imported closures, which are added again after restoring
+  closures: Vec<(String, usize)>,
+  import_sigs: Vec<(String, CalxImportSig)>,
+  import_decls: Vec<CalxImportDecl>,
+  stack: Vec<Calx>,
+  globals: Vec<Calx>,
+  initial_globals: Vec<Calx>,
+  frames: Vec<CalxFrameSnapshot>,
+  top_frame: CalxFrameSnapshot,
+  finished: bool,
+  return_value: Calx,
+  quit_code: Option<usize>,
+  fuel: u64,
+  fuel_costs: CalxFuelCosts,
+  timeout: Option<Duration>,
+  check_interval: u32,
+  max_call_depth: usize,
+  max_stack_len: usize,
+  max_memory: Option<usize>,
+}
+
+impl CalxVM {
+  /// encodes functions and complete running state into bytes, restore with `CalxVM::restore`.
+  /// a VM paused by `run_with_fuel` or stepped with `step` continues from where it was
+  pub fn snapshot(&self) -> Vec<u8> {
+    let snapshot = CalxSnapshot {
+      edition: CALX_INSTR_EDITION.to_owned(),
+      engine: self.engine,
+      funcs: self.funcs.to_owned(),
+      imports: self.import_table.iter().map(|x| x.name.to_string()).collect(),
+      closures: self
+        .import_table
+        .iter()
+        .filter(|x| matches!(x.f, CalxImportFn::Closure(_)))
+        .map(|x| (x.name.to_string(), x.arity))
+        .collect(),
+      import_sigs: self.import_sigs.iter().map(|(k, v)| (k.to_string(), v.to_owned())).collect(),
+      import_decls: self.import_decls.to_owned(),
+      stack: self.stack.to_owned(),
+      globals: self.globals.to_owned(),
+      initial_globals: self.initial_globals.to_owned(),
+      frames: self.frames.iter().map(|x| self.frame_snapshot(x)).collect(),
+      top_frame: self.frame_snapshot(&self.top_frame),
+      finished: self.finished,
+      return_value: self.return_value.to_owned(),
+      quit_code: self.quit_code,
+      fuel: self.fuel,
+      fuel_costs: self.fuel_costs,
+      timeout: self.timeout,
+      check_interval: self.check_interval,
+      max_call_depth: self.max_call_depth,
+      max_stack_len: self.max_stack_len,
+      max_memory: self.max_memory,
+    };
+    bincode::encode_to_vec(snapshot, bincode::config::standard()).expect("encode snapshot")
+  }
+
+  /// creates a VM from bytes of `snapshot`, imports should provide the same names as the VM snapshotted.
+  /// closures are not encoded, they return errors until added again with `add_import`
+  /// instructions are verified and frames checked against functions, broken bytes give errors
+  pub fn restore(bytes: &[u8], imports: CalxImportsDict) -> Result<Self, String> {
+    let (snapshot, _): (CalxSnapshot, usize) =
+      bincode::decode_from_slice(bytes, bincode::config::standard()).map_err(|e| format!("failed to decode snapshot: {e}"))?;
+    if snapshot.edition != CALX_INSTR_EDITION {
+      return Err(format!(
+        "snapshot encoded in edition {}, runner uses {}",
+        snapshot.edition, CALX_INSTR_EDITION
+      ));
+    }
+    let mut closures = HashMap::new();
+    for (name, arity) in snapshot.closures {
+      let f = placeholder_closure(format!("imported closure {name} should be added again after restoring"));
+      closures.insert(Rc::from(name), (f, arity));
+    }
+    // table is ordered as in snapshot, names added after `preprocess` were appended
+    let mut fresh = import_table(&imports, &closures);
+    let mut names: Vec<String> = fresh.iter().map(|x| x.name.to_string()).collect();
+    let mut expected = snapshot.imports.to_owned();
+    names.sort();
+    expected.sort();
+    if names != expected {
+      return Err(format!("snapshot expected imports {:?}, got {:?}", snapshot.imports, names));
+    }
+    let mut table = Vec::with_capacity(fresh.len());
+    for name in &snapshot.imports {
+      let i = fresh.iter().position(|x| *x.name == **name).expect("checked import name");
+      table.push(fresh.remove(i));
+    }
+
+    // bytes may come from elsewhere, so functions and frames are checked before running.
+    // functions without instructions are not preprocessed yet, `preprocess` checks them later
+    let funcs = snapshot.funcs;
+    let globals_limit = globals_limit(&funcs, snapshot.globals.len());
+    let mut messages: Vec<String> = vec![];
+    for (idx, f) in funcs.iter().enumerate() {
+      if !f.instrs.is_empty() {
+        if let Err(es) = verify_func(idx, &funcs, globals_limit, &table) {
+          messages.extend(es.iter().map(|e| e.to_string()));
+        }
+      }
+    }
+    if !messages.is_empty() {
+      return Err(format!("snapshot has invalid functions:\n{}", messages.join("\n")));
+    }
+    let stack_len = snapshot.stack.len();
+    let frames = snapshot
+      .frames
+      .into_iter()
+      .map(|x| restore_frame(&funcs, x, stack_len))
+      .collect::<Result<Vec<_>, String>>()?;
+    let top_frame = restore_frame(&funcs, snapshot.top_frame, stack_len)?;
+
+    let mut vm = CalxVM {
+      stack: snapshot.stack,
+      globals: snapshot.globals,
+      funcs,
+      frames,
+      top_frame,
+      import_table: table,
+      imports,
+      return_value: snapshot.return_value,
+      finished: snapshot.finished,
+      engine: snapshot.engine,
+      fuel: snapshot.fuel,
+      fuel_costs: snapshot.fuel_costs,
+      interrupt: CalxInterruptHandle::new(),
+      timeout: snapshot.timeout,
+      check_interval: snapshot.check_interval,
+      max_call_depth: snapshot.max_call_depth,
+      max_stack_len: snapshot.max_stack_len,
+      max_memory: snapshot.max_memory,
+      heap_estimate: 0,
+      quit_code: snapshot.quit_code,
+      initial_globals: snapshot.initial_globals,
+      import_closures: closures,
+      user_data: Default::default(),
+      import_sigs: snapshot.import_sigs.into_iter().map(|(k, v)| (Rc::from(k), v)).collect(),
+      import_decls: snapshot.import_decls,
+      active_imports: vec![],
+      nested_calls: 0,
+      reg_cache: None,
+    };
+    vm.rebuild_import_table();
+    Ok(vm)
+  }
+
+  fn frame_snapshot(&self, frame: &CalxFrame) -> CalxFrameSnapshot {
+    CalxFrameSnapshot {
+      func: self.funcs.iter().position(|f| f.name == frame.name),
+      locals: frame.locals.to_owned(),
+      pointer: frame.pointer,
+      initial_stack_size: frame.initial_stack_size,
+    }
+  }
+}
+
+/// frame with instructions of its function, pointer and locals have to fit in the function
+fn restore_frame(funcs: &[CalxFunc], frame: CalxFrameSnapshot, stack_len: usize) -> Result<CalxFrame, String> {
+  let (base, locals_limit) = match frame.func {
+    Some(idx) => match funcs.get(idx) {
+      Some(f) => (
+        CalxFrame {
+          name: f.name.to_owned(),
+          locals: vec![],
+          instrs: f.instrs.to_owned(),
+          pointer: 0,
+          initial_stack_size: 0,
+          ret_types: f.ret_types.to_owned(),
+        },
+        locals_limit(f),
+      ),
+      None => return Err(format!("snapshot refers to unknown function #{idx}")),
+    },
+    None => (CalxFrame::default(), 0),
+  };
+  if frame.pointer > base.instrs.len() {
+    return Err(format!(
+      "snapshot frame of {} points at {}, beyond {} instructions",
+      base.name,
+      frame.pointer,
+      base.instrs.len()
+    ));
+  }
+  if frame.locals.len() > locals_limit {
+    return Err(format!(
+      "snapshot frame of {} has {} locals, expected at most {locals_limit}",
+      base.name,
+      frame.locals.len()
+    ));
+  }
+  if frame.initial_stack_size > stack_len {
+    return Err(format!(
+      "snapshot frame of {} starts at stack size {}, beyond stack of {stack_len}",
+      base.name, frame.initial_stack_size
+    ));
+  }
+  Ok(CalxFrame {
+    locals: frame.locals,
+    pointer: frame.pointer,
+    initial_stack_size: frame.initial_stack_size,
+    ..base
+  })
+}
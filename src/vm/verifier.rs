@@ -0,0 +1,257 @@
+//! Verifier for instructions that did not come out of `preprocess`,
+//! for example instructions built by hand or loaded from a binary file.
+//!
+//! Checks are performed statically, so the VM does not need to trust the instructions:
+//! - jump targets stay inside the function, or land at its end
+//! - function, local, global and import references exist
+//! - stack depth at each instruction is consistent among all paths reaching it
+
+use crate::rc::Rc;
+use core::fmt;
+
+use super::func::CalxFunc;
+use super::import::CalxImport;
+use super::instr::CalxInstr;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd)]
+pub enum CalxVerifyErrorKind {
+  /// jump target outside of `0..=instrs.len()`
+  InvalidJump(i64),
+  /// `call` or `return-call` to a function index that does not exist
+  UnknownFunc(usize),
+  /// local index beyond params and locals of the function, counting slots added by `local.new`
+  UnknownLocal(usize),
+  /// global index beyond globals, counting slots added by `global.new`
+  UnknownGlobal(usize),
+  /// `call-import` with an index beyond the import table
+  UnknownImport(usize),
+  /// instruction pops more values than the stack holds
+  StackUnderflow { expected: usize, found: usize },
+  /// paths reaching an instruction disagree on stack depth
+  StackMismatch { expected: usize, found: usize },
+  /// stack depth does not match return types at `return` or at function end
+  ReturnSize { expected: usize, found: usize },
+  /// `return-call` requires only callee arguments on the stack and same return size
+  ReturnCallMismatch(usize),
+}
+
+impl fmt::Display for CalxVerifyErrorKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::InvalidJump(target) => write!(f, "invalid jump target {target}"),
+      Self::UnknownFunc(idx) => write!(f, "unknown function index {idx}"),
+      Self::UnknownLocal(idx) => write!(f, "unknown local index {idx}"),
+      Self::UnknownGlobal(idx) => write!(f, "unknown global index {idx}"),
+      Self::UnknownImport(idx) => write!(f, "unknown import #{idx}"),
+      Self::StackUnderflow { expected, found } => write!(f, "stack underflow, expected {expected} values, found {found}"),
+      Self::StackMismatch { expected, found } => write!(f, "stack depth mismatch, expected {expected}, found {found}"),
+      Self::ReturnSize { expected, found } => write!(f, "return size mismatch, expected {expected}, found {found}"),
+      Self::ReturnCallMismatch(idx) => write!(f, "stack shape does not fit return-call to function {idx}"),
+    }
+  }
+}
+
+/// error found during verification, located by function name and instruction index
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd)]
+pub struct CalxVerifyError {
+  pub func: Rc<str>,
+  pub pointer: usize,
+  pub kind: CalxVerifyErrorKind,
+}
+
+impl fmt::Display for CalxVerifyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}@{}: {}", self.func, self.pointer, self.kind)
+  }
+}
+
+/// verify instructions of all functions, returns every error found.
+/// `globals_size` is the number of globals the VM starts with, each `global.new` in the program counts as one more slot.
+pub fn verify_funcs(funcs: &[CalxFunc], globals_size: usize, imports: &[CalxImport]) -> Result<(), Vec<CalxVerifyError>> {
+  let globals_limit = globals_limit(funcs, globals_size);
+
+  let mut errors = vec![];
+  for idx in 0..funcs.len() {
+    if let Err(es) = verify_func(idx, funcs, globals_limit, imports) {
+      errors.extend(es);
+    }
+  }
+
+  if errors.is_empty() {
+    Ok(())
+  } else {
+    Err(errors)
+  }
+}
+
+/// globals that may exist while running, those from start and one for each `global.new`
+pub(crate) fn globals_limit(funcs: &[CalxFunc], globals_size: usize) -> usize {
+  let global_news = funcs
+    .iter()
+    .map(|f| f.instrs.iter().filter(|x| matches!(x, CalxInstr::GlobalNew)).count())
+    .sum::<usize>();
+  globals_size + global_news
+}
+
+/// locals a frame of `f` may hold, params or `locals_size`, and one for each `local.new`
+pub(crate) fn locals_limit(f: &CalxFunc) -> usize {
+  f.locals_size.max(f.params_types.len()) + f.instrs.iter().filter(|x| matches!(x, CalxInstr::LocalNew)).count()
+}
+
+/// verify a single function, returns stack depth before each instruction, `None` for unreachable ones.
+/// the extra last item is the depth at function end.
+pub fn verify_func(
+  idx: usize,
+  funcs: &[CalxFunc],
+  globals_limit: usize,
+  imports: &[CalxImport],
+) -> Result<Vec<Option<usize>>, Vec<CalxVerifyError>> {
+  let f = match funcs.get(idx) {
+    Some(f) => f,
+    None => {
+      return Err(vec![CalxVerifyError {
+        func: Rc::from(format!("#{idx}")),
+        pointer: 0,
+        kind: CalxVerifyErrorKind::UnknownFunc(idx),
+      }])
+    }
+  };
+  let instrs = &f.instrs;
+  let size = instrs.len();
+  let locals_limit = locals_limit(f);
+  let mut errors: Vec<CalxVerifyError> = vec![];
+  let fail = |pointer: usize, kind: CalxVerifyErrorKind| CalxVerifyError {
+    func: f.name.to_owned(),
+    pointer,
+    kind,
+  };
+
+  // operands are checked even in unreachable code
+  for (pointer, instr) in instrs.iter().enumerate() {
+    match instr {
+      CalxInstr::Call(i) | CalxInstr::ReturnCall(i) if *i >= funcs.len() => {
+        errors.push(fail(pointer, CalxVerifyErrorKind::UnknownFunc(*i)))
+      }
+      CalxInstr::GlobalGet(i) | CalxInstr::GlobalSet(i) if *i >= globals_limit => {
+        errors.push(fail(pointer, CalxVerifyErrorKind::UnknownGlobal(*i)))
+      }
+      CalxInstr::CallImport(i) if *i >= imports.len() => errors.push(fail(pointer, CalxVerifyErrorKind::UnknownImport(*i))),
+      CalxInstr::LocalGet(i)
+      | CalxInstr::LocalSet(i)
+      | CalxInstr::LocalTee(i)
+      | CalxInstr::IntAddLocalConst(i, _)
+      | CalxInstr::LocalIncrease(i, _)
+      | CalxInstr::JmpIfLocalConst { local: i, .. }
+        if *i >= locals_limit =>
+      {
+        errors.push(fail(pointer, CalxVerifyErrorKind::UnknownLocal(*i)))
+      }
+      CalxInstr::IntAddLocals(a, b) if (*a).max(*b) >= locals_limit => {
+        errors.push(fail(pointer, CalxVerifyErrorKind::UnknownLocal((*a).max(*b))))
+      }
+      _ => {
+        if let Some(target) = instr.jump_target(pointer) {
+          if target < 0 || target > size as i64 {
+            errors.push(fail(pointer, CalxVerifyErrorKind::InvalidJump(target)));
+          }
+        }
+      }
+    }
+  }
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+
+  let ret_size = f.ret_types.len();
+  let mut depths: Vec<Option<usize>> = vec![None; size + 1];
+  depths[0] = Some(0);
+  let mut pending: Vec<usize> = vec![0];
+
+  while let Some(pointer) = pending.pop() {
+    let depth = depths[pointer].expect("depth of pending instruction");
+    if pointer == size {
+      if depth != ret_size {
+        errors.push(fail(
+          pointer,
+          CalxVerifyErrorKind::ReturnSize {
+            expected: ret_size,
+            found: depth,
+          },
+        ));
+      }
+      continue;
+    }
+
+    let instr = &instrs[pointer];
+    let (params_size, ret_size_of_instr) = match instr {
+      CalxInstr::Call(i) => (funcs[*i].params_types.len(), funcs[*i].ret_types.len()),
+      CalxInstr::CallImport(i) => (imports[*i].arity, imports[*i].ret_size()),
+      CalxInstr::Return => (ret_size, 0),
+      CalxInstr::ReturnCall(i) => (funcs[*i].params_types.len(), 0),
+      a => a.stack_arity(),
+    };
+    if depth < params_size {
+      errors.push(fail(
+        pointer,
+        CalxVerifyErrorKind::StackUnderflow {
+          expected: params_size,
+          found: depth,
+        },
+      ));
+      continue;
+    }
+    let next_depth = depth - params_size + ret_size_of_instr;
+
+    let mut targets: Vec<usize> = vec![];
+    match instr {
+      CalxInstr::Return => {
+        if depth != ret_size {
+          errors.push(fail(
+            pointer,
+            CalxVerifyErrorKind::ReturnSize {
+              expected: ret_size,
+              found: depth,
+            },
+          ));
+        }
+      }
+      CalxInstr::ReturnCall(i) => {
+        if depth != funcs[*i].params_types.len() || funcs[*i].ret_types.len() != ret_size {
+          errors.push(fail(pointer, CalxVerifyErrorKind::ReturnCallMismatch(*i)));
+        }
+      }
+      CalxInstr::Quit(_) | CalxInstr::Unreachable => {}
+      a => {
+        if a.jump_target(pointer).is_none() || a.is_conditional_jump() {
+          targets.push(pointer + 1);
+        }
+        if let Some(target) = a.jump_target(pointer) {
+          targets.push(target as usize);
+        }
+      }
+    }
+
+    for target in targets {
+      match depths[target] {
+        None => {
+          depths[target] = Some(next_depth);
+          pending.push(target);
+        }
+        Some(expected) if expected != next_depth => errors.push(fail(
+          target,
+          CalxVerifyErrorKind::StackMismatch {
+            expected,
+            found: next_depth,
+          },
+        )),
+        Some(_) => {}
+      }
+    }
+  }
+
+  if errors.is_empty() {
+    Ok(depths)
+  } else {
+    Err(errors)
+  }
+}
@@ -0,0 +1,34 @@
+//! helpers shared by test files, not every file uses all of them
+#![allow(dead_code)]
+
+use calx_vm::rc::Rc;
+
+use cirru_parser::{parse, Cirru};
+
+use calx_vm::{parse_function, CalxFunc, CalxInlineHint, CalxInstr, CalxType};
+
+/// functions from Cirru code, before `preprocess`
+pub fn parse_program(code: &str) -> Result<Vec<CalxFunc>, String> {
+  let mut fns = vec![];
+  for x in parse(code)? {
+    if let Cirru::List(ys) = x {
+      fns.push(parse_function(&ys)?);
+    }
+  }
+  Ok(fns)
+}
+
+/// function with `i64` params and returns, built from instructions that `preprocess` would not generate
+pub fn make_func(name: &str, params_size: usize, ret_size: usize, instrs: Vec<CalxInstr>) -> CalxFunc {
+  CalxFunc {
+    name: Rc::from(name),
+    params_types: Rc::new(vec![CalxType::I64; params_size]),
+    ret_types: Rc::new(vec![CalxType::I64; ret_size]),
+    syntax: Rc::new(vec![]),
+    instrs: Rc::new(instrs),
+    syntax_map: Rc::new(vec![]),
+    locals_size: 0,
+    inline_hint: CalxInlineHint::Auto,
+    local_names: Rc::new(vec![]),
+  }
+}
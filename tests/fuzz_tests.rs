@@ -0,0 +1,226 @@
+#![allow(clippy::result_large_err)]
+// imported functions take `&Vec<Calx>`
+#![allow(clippy::ptr_arg)]
+
+use std::{
+  collections::HashMap,
+  panic::{self, AssertUnwindSafe},
+  time::Duration,
+};
+
+use calx_vm::rc::Rc;
+use calx_vm::{Calx, CalxEngine, CalxError, CalxFunc, CalxImportsDict, CalxInlineHint, CalxInstr, CalxIntCmp, CalxType, CalxVM};
+
+/// xorshift, enough for generating instructions with reproducible seeds
+struct Rng(u64);
+
+impl Rng {
+  fn next(&mut self) -> u64 {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    self.0
+  }
+
+  fn below(&mut self, n: usize) -> usize {
+    (self.next() % n as u64) as usize
+  }
+
+  fn int(&mut self) -> i64 {
+    match self.below(4) {
+      0 => 0,
+      1 => i64::MAX,
+      2 => i64::MIN,
+      _ => self.below(200) as i64 - 100,
+    }
+  }
+}
+
+fn first_value(xs: &Vec<Calx>) -> Result<Calx, CalxError> {
+  Ok(xs[0].to_owned())
+}
+
+fn random_value(rng: &mut Rng) -> Calx {
+  match rng.below(6) {
+    0 => Calx::Nil,
+    1 => Calx::Bool(rng.below(2) == 0),
+    2 => Calx::F64(rng.int() as f64 / 3.0),
+    3 => Calx::Str(Rc::from("s")),
+    _ => Calx::I64(rng.int()),
+  }
+}
+
+/// indexes go a little beyond valid ones, so that out of range cases are covered
+fn random_instr(rng: &mut Rng, size: usize) -> CalxInstr {
+  use CalxInstr::*;
+  let idx = rng.below(5);
+  let line = rng.below(size + 3);
+  let offset = rng.below(12) as i32 - 6;
+  let cmps = [
+    CalxIntCmp::Eq,
+    CalxIntCmp::Ne,
+    CalxIntCmp::Lt,
+    CalxIntCmp::Le,
+    CalxIntCmp::Gt,
+    CalxIntCmp::Ge,
+  ];
+  let simple = [
+    LocalNew,
+    GlobalNew,
+    Dup,
+    Drop,
+    IntAdd,
+    IntMul,
+    IntDiv,
+    IntRem,
+    IntNeg,
+    IntShr,
+    IntShl,
+    IntEq,
+    IntNe,
+    IntLt,
+    IntLe,
+    IntGt,
+    IntGe,
+    Add,
+    Mul,
+    Div,
+    Neg,
+    NewList,
+    ListGet,
+    ListSet,
+    NewLink,
+    And,
+    Or,
+    Not,
+    Unreachable,
+    Nop,
+    Return,
+  ];
+  match rng.below(24) {
+    0 => LocalSet(idx),
+    1 => LocalTee(idx),
+    2 => LocalGet(idx),
+    3 => GlobalSet(idx),
+    4 => GlobalGet(idx),
+    5..=7 => Const(random_value(rng)),
+    8 => Jmp(line),
+    9 => JmpOffset(offset),
+    10 => JmpIf(line),
+    11 => JmpOffsetIf(offset),
+    12 => Call(rng.below(4)),
+    13 => ReturnCall(rng.below(4)),
+    14 => CallImport(rng.below(2)),
+    15 => Quit(idx),
+    16 => Assert(Rc::from("fuzz")),
+    17 => IntAddLocals(idx, rng.below(5)),
+    18 => IntAddLocalConst(idx, rng.int()),
+    19 => LocalIncrease(idx, rng.int()),
+    20 => JmpIfLocalConst {
+      local: idx,
+      cmp: cmps[rng.below(cmps.len())],
+      value: rng.int(),
+      to: line,
+    },
+    _ => simple[rng.below(simple.len())].to_owned(),
+  }
+}
+
+fn random_func(rng: &mut Rng, name: &str, params: usize) -> CalxFunc {
+  let size = 1 + rng.below(24);
+  let instrs: Vec<CalxInstr> = (0..size).map(|_| random_instr(rng, size)).collect();
+  CalxFunc {
+    name: Rc::from(name),
+    params_types: Rc::new(vec![CalxType::I64; params]),
+    ret_types: Rc::new(vec![CalxType::I64; rng.below(2)]),
+    syntax: Rc::new(vec![]),
+    instrs: Rc::new(instrs),
+    syntax_map: Rc::new(vec![]),
+    local_names: Rc::new(vec![]),
+    locals_size: rng.below(4),
+    inline_hint: CalxInlineHint::Auto,
+  }
+}
+
+/// runs instructions directly without preprocessing, so stacks are not checked beforehand
+fn run_random_program(seed: u64, engine: CalxEngine) {
+  let mut rng = Rng(seed);
+  let funcs = vec![
+    random_func(&mut rng, "main", 0),
+    random_func(&mut rng, "f1", 1),
+    random_func(&mut rng, "f2", 2),
+  ];
+  let mut imports: CalxImportsDict = HashMap::new();
+  imports.insert(Rc::from("first"), (first_value, 1));
+
+  let mut vm = CalxVM::new_with_engine(funcs, vec![Calx::I64(0), Calx::Nil], imports, engine);
+  vm.max_call_depth = 64;
+  vm.setup_top_frame().expect("main exists");
+  // results do not matter, only that errors are returned instead of panics
+  match engine {
+    CalxEngine::Stack => {
+      let _ = vm.run_with_fuel(vec![], 2_000);
+    }
+    // register engine verifies instructions before lowering, so loops are bounded by interrupt instead of fuel
+    CalxEngine::Register => {
+      vm.timeout = Some(Duration::from_millis(20));
+      let _ = vm.run(vec![]);
+    }
+  }
+}
+
+#[test]
+fn test_random_instrs_no_panic() {
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    for seed in 1..=3000u64 {
+      let seed = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+      let result = panic::catch_unwind(AssertUnwindSafe(|| run_random_program(seed, engine)));
+      assert!(result.is_ok(), "panicked on seed {seed} with {engine:?}");
+    }
+  }
+}
+
+#[test]
+fn test_malformed_stacks() {
+  use CalxInstr::*;
+  let cases = vec![
+    vec![IntAdd],
+    vec![Const(Calx::I64(1)), IntAdd],
+    vec![IntNeg],
+    vec![Neg],
+    vec![Dup],
+    vec![JmpIf(0)],
+    vec![Const(Calx::I64(1)), Const(Calx::I64(0)), IntDiv],
+    vec![Const(Calx::I64(1)), Const(Calx::I64(0)), IntRem],
+    vec![Const(Calx::I64(1)), Const(Calx::I64(64)), IntShl],
+    vec![JmpOffset(-3)],
+    vec![Call(9)],
+    vec![NewList],
+    vec![Const(Calx::I64(i64::MAX)), Const(Calx::I64(1)), IntAdd],
+    vec![Const(Calx::I64(i64::MIN)), Const(Calx::I64(2)), IntMul],
+    vec![Const(Calx::I64(i64::MIN)), IntNeg],
+    vec![Const(Calx::I64(i64::MAX)), Const(Calx::I64(1)), Add],
+  ];
+  for (instrs, engine) in cases
+    .iter()
+    .flat_map(|instrs| [(instrs, CalxEngine::Stack), (instrs, CalxEngine::Register)])
+  {
+    let main = CalxFunc {
+      name: Rc::from("main"),
+      params_types: Rc::new(vec![]),
+      ret_types: Rc::new(vec![]),
+      syntax: Rc::new(vec![]),
+      instrs: Rc::new(instrs.to_owned()),
+      syntax_map: Rc::new(vec![]),
+      local_names: Rc::new(vec![]),
+      locals_size: 0,
+      inline_hint: CalxInlineHint::Auto,
+    };
+    let mut vm = CalxVM::new_with_engine(vec![main], vec![], HashMap::new(), engine);
+    vm.setup_top_frame().expect("main exists");
+    assert!(
+      vm.run(vec![]).into_result().is_err(),
+      "expected error from {instrs:?} with {engine:?}"
+    );
+  }
+}
@@ -0,0 +1,550 @@
+use calx_vm::rc::Rc;
+use std::collections::HashMap;
+
+use calx_vm::{
+  compact_instrs, convert_tail_calls, fold_constants, fuse_instrs, inline_funcs, thread_jumps, Calx, CalxFunc, CalxInlineHint,
+  CalxInstr, CalxIntCmp, CalxPass, CalxPassManager, CalxPassStage, CalxSyntax, CalxTailCallMissKind, CalxVM, CALX_INLINE_SIZE,
+};
+
+mod common;
+use common::{make_func, parse_program};
+
+fn run_program(fns: Vec<CalxFunc>) -> Result<Calx, String> {
+  let mut vm = CalxVM::new(fns, vec![], HashMap::new());
+  vm.preprocess(false)?;
+  vm.setup_top_frame()?;
+  vm.run(vec![]).into_result().map_err(|e| e.message)
+}
+
+#[test]
+fn test_fold_arithmetic() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  i.add
+    const 1
+    i.mul
+      const 2
+      const 3
+  const 10
+  i.div
+    const 1
+    const 0
+  drop
+  return
+"#;
+  let fns = parse_program(code)?;
+  let mut folded = fns.to_owned();
+  fold_constants(&mut folded[0]);
+
+  assert_eq!(
+    *folded[0].syntax,
+    vec![
+      CalxSyntax::Const(Calx::I64(7)),
+      CalxSyntax::Const(Calx::I64(10)),
+      // division by zero is left for runtime
+      CalxSyntax::Const(Calx::I64(1)),
+      CalxSyntax::Const(Calx::I64(0)),
+      CalxSyntax::IntDiv,
+      CalxSyntax::Drop,
+      CalxSyntax::Return,
+    ]
+  );
+
+  Ok(())
+}
+
+#[test]
+fn test_fold_constant_if() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  const 0
+  const 1
+  if (-> i64)
+    do
+      const 2
+      const 1
+      i.eq
+      if (-> i64)
+        do (const 10)
+        do (const 20)
+    do
+      const 30
+  i.add
+  block (i64 -> i64)
+    const 1
+    i.add
+  return
+"#;
+  let fns = parse_program(code)?;
+  let expected = run_program(fns.to_owned())?;
+  assert_eq!(expected, Calx::I64(21));
+
+  let mut folded = fns.to_owned();
+  fold_constants(&mut folded[0]);
+  assert!(!folded[0].syntax.iter().any(|x| matches!(x, CalxSyntax::If { .. })));
+  assert_eq!(run_program(folded)?, expected);
+
+  Ok(())
+}
+
+fn run_preprocessed(fns: Vec<CalxFunc>, pass: fn(&mut CalxFunc)) -> Result<(Calx, Vec<CalxFunc>), String> {
+  let mut vm = CalxVM::new(fns, vec![], HashMap::new());
+  vm.preprocess(false)?;
+  for f in &mut vm.funcs {
+    pass(f);
+  }
+  if let Err(es) = vm.verify() {
+    return Err(es[0].to_string());
+  }
+  vm.setup_top_frame()?;
+  let ret = vm.run(vec![]).into_result().map_err(|e| e.message)?;
+  Ok((ret, vm.funcs))
+}
+
+const FIBO_SUM_CODE: &str = r#"
+fn main (-> i64)
+  call fibo
+    const 16
+  call sum
+    const 100
+  i.add
+  return
+
+fn fibo (($x i64) -> i64)
+  local.get $x
+  const 3
+  i.lt
+  if (->)
+    do
+      const 1
+      return
+    do
+      call fibo
+        i.add (local.get $x) (const -1)
+      call fibo
+        i.add (local.get $x) (const -2)
+      i.add
+      return
+
+fn sum (($n i64) -> i64)
+  local.new
+  local.new
+  const 0
+  local.set 1
+  const 0
+  local.set 2
+  block (->)
+    loop (->)
+      local.get 2
+      local.get 0
+      i.gt
+      br-if 1
+      local.get 1
+      local.get 2
+      i.add
+      local.set 1
+      local.get 2
+      const 1
+      i.add
+      local.set 2
+      br 0
+  local.get 1
+  return
+"#;
+
+#[test]
+fn test_fuse_instrs() -> Result<(), String> {
+  let fns = parse_program(FIBO_SUM_CODE)?;
+  let (expected, _) = run_preprocessed(fns.to_owned(), |_| {})?;
+  assert_eq!(expected, Calx::I64(987 + 5050));
+
+  let (ret, funcs) = run_preprocessed(fns, fuse_instrs)?;
+  assert_eq!(ret, expected);
+  let fused = funcs
+    .iter()
+    .flat_map(|f| f.instrs.iter())
+    .filter(|x| {
+      matches!(
+        x,
+        CalxInstr::IntAddLocals(..)
+          | CalxInstr::IntAddLocalConst(..)
+          | CalxInstr::LocalIncrease(..)
+          | CalxInstr::JmpIfLocalConst { .. }
+      )
+    })
+    .count();
+  assert_eq!(fused, 5);
+
+  Ok(())
+}
+
+/// sequences with jumps landing inside are kept
+#[test]
+fn test_fuse_instrs_jump_targets() {
+  let instrs = vec![
+    CalxInstr::Const(Calx::Bool(true)),
+    CalxInstr::JmpIf(3),
+    CalxInstr::LocalGet(0),
+    CalxInstr::Const(Calx::I64(1)),
+    CalxInstr::IntAdd,
+    CalxInstr::LocalGet(0),
+    CalxInstr::Const(Calx::I64(1)),
+    CalxInstr::IntAdd,
+    CalxInstr::Jmp(9),
+    CalxInstr::Drop,
+  ];
+  let mut f = make_func("main", 1, 0, instrs);
+  fuse_instrs(&mut f);
+  assert_eq!(
+    *f.instrs,
+    vec![
+      CalxInstr::Const(Calx::Bool(true)),
+      CalxInstr::JmpIf(3),
+      CalxInstr::LocalGet(0),
+      CalxInstr::Const(Calx::I64(1)),
+      CalxInstr::IntAdd,
+      CalxInstr::IntAddLocalConst(0, 1),
+      CalxInstr::Jmp(7),
+      CalxInstr::Drop,
+    ]
+  );
+}
+
+#[test]
+fn test_compact_instrs() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  const 0
+  local.set 0
+  block (->)
+    loop (->)
+      local.get 0
+      const 10
+      i.ge
+      br-if 1
+      local.get 0
+      const 1
+      i.add
+      local.set 0
+      br 0
+  local.get 0
+  return
+  const 1
+  drop
+"#;
+  let fns = parse_program(code)?;
+  let (expected, _) = run_preprocessed(fns.to_owned(), |_| {})?;
+  assert_eq!(expected, Calx::I64(10));
+
+  let (ret, funcs) = run_preprocessed(fns, compact_instrs)?;
+  assert_eq!(ret, expected);
+  assert!(!funcs[0].instrs.iter().any(|x| matches!(x, CalxInstr::Nop)));
+  assert_eq!(funcs[0].instrs.len(), funcs[0].syntax.len() - 6);
+  assert_eq!(funcs[0].instrs.last(), Some(&CalxInstr::Return));
+
+  Ok(())
+}
+
+/// errors report positions in syntax after instructions are moved
+#[test]
+fn test_compact_instrs_syntax_index() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  block (->)
+    block (->)
+      const 1
+      drop
+  const 1
+  const true
+  i.add
+  return
+"#;
+  let mut vm = CalxVM::new(parse_program(code)?, vec![], HashMap::new());
+  vm.preprocess(false)?;
+  for f in &mut vm.funcs {
+    compact_instrs(f);
+  }
+  assert_eq!(vm.funcs[0].instrs[4], CalxInstr::IntAdd);
+  vm.setup_top_frame()?;
+  let e = vm.run(vec![]).into_result().expect_err("adding bool");
+  assert_eq!(vm.funcs[0].syntax[e.syntax_index.expect("syntax index")], CalxSyntax::IntAdd);
+  assert_eq!(e.syntax_index, Some(8));
+  assert!(e.to_string().contains("\nat syntax 8"), "{e}");
+
+  Ok(())
+}
+
+#[test]
+fn test_thread_jumps() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  local.new
+  local.new
+  const 0
+  local.set 0
+  const 0
+  local.set 1
+  block (->)
+    loop (->)
+      local.get 0
+      const 10
+      i.ge
+      br-if 1
+      local.get 0
+      const 4
+      i.lt
+      if (->)
+        do
+          local.get 1
+          const 1
+          i.add
+          local.set 1
+      local.get 0
+      const 1
+      i.add
+      local.set 0
+      br 0
+  local.get 1
+  return
+"#;
+  let fns = parse_program(code)?;
+  let (expected, _) = run_preprocessed(fns.to_owned(), |_| {})?;
+  assert_eq!(expected, Calx::I64(4));
+
+  let (ret, funcs) = run_preprocessed(fns, |f| {
+    thread_jumps(f);
+    compact_instrs(f);
+  })?;
+  assert_eq!(ret, expected);
+  let instrs = &funcs[0].instrs;
+  for (pointer, instr) in instrs.iter().enumerate() {
+    if let Some(target) = instr.jump_target(pointer) {
+      assert_ne!(target, pointer as i64 + 1, "jump to next at {pointer}");
+      assert!(
+        !matches!(instrs.get(target as usize), Some(CalxInstr::Jmp(_))),
+        "jump chain at {pointer}"
+      );
+    }
+  }
+  // `if` without else branch jumps with inverted condition
+  assert_eq!(instrs.iter().filter(|x| **x == CalxInstr::IntGe).count(), 2);
+  assert!(!instrs.contains(&CalxInstr::IntLt));
+
+  // a missing local is still reported, so the jump is kept
+  let mut f = make_func(
+    "main",
+    0,
+    0,
+    vec![
+      CalxInstr::JmpIfLocalConst {
+        local: 3,
+        cmp: CalxIntCmp::Lt,
+        value: 1,
+        to: 1,
+      },
+      CalxInstr::Nop,
+    ],
+  );
+  thread_jumps(&mut f);
+  assert!(matches!(f.instrs[0], CalxInstr::JmpIfLocalConst { local: 3, .. }));
+
+  Ok(())
+}
+
+#[test]
+fn test_inline_funcs() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  local.new
+  const 0
+  local.set 0
+  block (->)
+    loop (->)
+      local.get 0
+      const 100
+      i.ge
+      br-if 1
+      call step
+        local.get 0
+        const 3
+      local.set 0
+      br 0
+  call add
+    local.get 0
+    const 5
+  return
+
+fn step (($x i64) ($k i64) -> i64) inline
+  local.get $x
+  local.get $k
+  i.lt
+  if (->)
+    do
+      local.get $x
+      const 1
+      i.add
+      return
+  local.get $x
+  local.get $k
+  i.add
+  return
+
+fn add (($a i64) ($b i64) -> i64) noinline
+  local.get $a
+  local.get $b
+  i.add
+  return
+"#;
+  let fns = parse_program(code)?;
+  assert_eq!(fns[1].inline_hint, CalxInlineHint::Always);
+  assert_eq!(fns[2].inline_hint, CalxInlineHint::Never);
+
+  let run_inlined = |fns: Vec<CalxFunc>, max_size: usize| -> Result<(Calx, Vec<CalxFunc>), String> {
+    let mut vm = CalxVM::new(fns, vec![], HashMap::new());
+    vm.preprocess(false)?;
+    inline_funcs(&mut vm.funcs, max_size);
+    if let Err(es) = vm.verify() {
+      return Err(es[0].to_string());
+    }
+    vm.setup_top_frame()?;
+    let ret = vm.run(vec![]).into_result().map_err(|e| e.message)?;
+    Ok((ret, vm.funcs))
+  };
+
+  let (expected, _) = run_preprocessed(fns.to_owned(), |_| {})?;
+  assert_eq!(expected, Calx::I64(107));
+
+  for max_size in [0, CALX_INLINE_SIZE] {
+    let (ret, funcs) = run_inlined(fns.to_owned(), max_size)?;
+    assert_eq!(ret, expected);
+    let calls: Vec<&CalxInstr> = funcs[0].instrs.iter().filter(|x| matches!(x, CalxInstr::Call(_))).collect();
+    assert_eq!(calls, vec![&CalxInstr::Call(2)]);
+    // params of `step` take extra locals after `main`'s own local
+    assert_eq!(funcs[0].locals_size, 3);
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_convert_tail_calls() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  call count
+    const 100000
+    const 0
+  call fibo
+    const 10
+  i.add
+  return
+
+fn count (($n i64) ($acc i64) -> i64)
+  local.get $n
+  const 0
+  i.le
+  if (->)
+    do
+      local.get $acc
+      return
+  local.get $n
+  const -1
+  i.add
+  local.get $acc
+  local.get $n
+  i.add
+  call count
+  return
+
+fn fibo (($x i64) -> i64)
+  local.get $x
+  const 3
+  i.lt
+  if (->)
+    do
+      const 1
+      return
+  call fibo
+    i.add (local.get $x) (const -1)
+  call fibo
+    i.add (local.get $x) (const -2)
+  i.add
+  return
+"#;
+  let mut vm = CalxVM::new(parse_program(code)?, vec![], HashMap::new());
+  vm.preprocess(false)?;
+  let misses = convert_tail_calls(&mut vm.funcs);
+  if let Err(es) = vm.verify() {
+    return Err(es[0].to_string());
+  }
+
+  assert!(vm.funcs[1].instrs.contains(&CalxInstr::ReturnCall(1)));
+  assert!(!vm.funcs[1].instrs.contains(&CalxInstr::Call(1)));
+  // calls from main are not recursive, and not in tail position
+  assert!(!vm.funcs[0].instrs.iter().any(|x| matches!(x, CalxInstr::ReturnCall(_))));
+  assert_eq!(misses.len(), 2);
+  assert!(misses
+    .iter()
+    .all(|m| &*m.func == "fibo" && &*m.callee == "fibo" && m.kind == CalxTailCallMissKind::NotInTailPosition));
+
+  vm.setup_top_frame()?;
+  let ret = vm.run(vec![]).into_result().map_err(|e| e.message)?;
+  assert_eq!(ret, Calx::I64(5000050000 + 55));
+
+  Ok(())
+}
+
+struct ShrinkSum;
+
+impl CalxPass for ShrinkSum {
+  fn name(&self) -> &str {
+    "shrink-sum"
+  }
+
+  fn stage(&self) -> CalxPassStage {
+    CalxPassStage::Syntax
+  }
+
+  fn run(&self, funcs: &mut [CalxFunc]) {
+    let syntax: Vec<CalxSyntax> = funcs[0]
+      .syntax
+      .iter()
+      .map(|x| match x {
+        CalxSyntax::Const(Calx::I64(100)) => CalxSyntax::Const(Calx::I64(10)),
+        _ => x.to_owned(),
+      })
+      .collect();
+    funcs[0].syntax = Rc::new(syntax);
+  }
+}
+
+#[test]
+fn test_pass_manager() -> Result<(), String> {
+  let run_level = |level: u8, custom: bool| -> Result<Calx, String> {
+    let mut passes = CalxPassManager::with_level(level);
+    if custom {
+      passes.add(ShrinkSum);
+    }
+    let mut fns = parse_program(FIBO_SUM_CODE)?;
+    passes.run_syntax(&mut fns);
+    let mut vm = CalxVM::new(fns, vec![], HashMap::new());
+    vm.preprocess(false)?;
+    passes.run_instrs(&mut vm.funcs);
+    if let Err(es) = vm.verify() {
+      return Err(es[0].to_string());
+    }
+    vm.setup_top_frame()?;
+    vm.run(vec![]).into_result().map_err(|e| e.message)
+  };
+
+  assert!(CalxPassManager::with_level(0).names().is_empty());
+  assert_eq!(
+    CalxPassManager::with_level(3).names(),
+    vec!["fold-constants", "inline", "tail-calls", "thread-jumps", "compact", "fuse"]
+  );
+  for level in 0..=3 {
+    assert_eq!(run_level(level, false)?, Calx::I64(987 + 5050));
+    assert_eq!(run_level(level, true)?, Calx::I64(987 + 55));
+  }
+
+  Ok(())
+}
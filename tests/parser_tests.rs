@@ -1,6 +1,8 @@
-use cirru_parser::Cirru;
+use std::collections::HashMap;
 
-use calx_vm::extract_nested;
+use cirru_parser::{parse, Cirru};
+
+use calx_vm::{extract_nested, parse_function, CalxSyntax, CalxVM};
 
 /// extracting nested expression inside
 /// block and loop are special need to handle
@@ -38,3 +40,43 @@ fn test_extracting() -> Result<(), String> {
 
   Ok(())
 }
+
+/// blocks inside branches of `if` are located by their positions in the function
+#[test]
+fn test_if_branch_positions() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  const 1
+  const 1
+  if (-> i64)
+    do
+      block (-> i64)
+        const 2
+    do
+      const 3
+      drop
+      block (-> i64)
+        const 4
+  i.add
+  return
+"#;
+  let mut fns = vec![];
+  for x in parse(code)? {
+    if let Cirru::List(ys) = x {
+      fns.push(parse_function(&ys)?);
+    }
+  }
+  let syntax = &fns[0].syntax;
+  let mut blocks = 0;
+  for (idx, x) in syntax.iter().enumerate() {
+    if let CalxSyntax::Block { from, to, .. } = x {
+      blocks += 1;
+      assert_eq!(*from, idx + 1, "block at {idx}");
+      assert_eq!(syntax[*to], CalxSyntax::BlockEnd(false), "end of block at {idx}");
+    }
+  }
+  assert_eq!(blocks, 2);
+
+  // both branches start with the stack before `if`
+  CalxVM::new(fns, vec![], HashMap::new()).preprocess(false)
+}
@@ -0,0 +1,200 @@
+#![allow(clippy::result_large_err)]
+
+use std::collections::HashMap;
+
+use calx_vm::{fuse_instrs, Calx, CalxEngine, CalxError, CalxFunc, CalxInstr, CalxVM};
+
+mod common;
+use common::parse_program;
+
+const CODE: &str = r#"
+fn main (-> i64)
+  global.new
+  const 2
+  global.set 0
+  call sum
+    const 1000
+  call fibo
+    const 20
+  i.add
+  call count
+    const 100
+    const 0
+  i.add
+  global.get 0
+  i.add
+  return
+
+fn sum (($n i64) -> i64)
+  local.new
+  local.new
+  const 0
+  local.set 1
+  const 0
+  local.set 2
+  block (->)
+    loop (->)
+      local.get 2
+      local.get 0
+      i.gt
+      br-if 1
+      local.get 1
+      local.get 2
+      i.add
+      local.set 1
+      local.get 2
+      const 1
+      i.add
+      local.set 2
+      br 0
+  local.get 1
+  return
+
+fn fibo (($x i64) -> i64)
+  local.get $x
+  const 3
+  i.lt
+  if (->)
+    do
+      const 1
+      return
+  call fibo
+    i.add (local.get $x) (const -1)
+  call fibo
+    i.add (local.get $x) (const -2)
+  i.add
+  return
+
+fn count (($n i64) ($acc i64) -> i64)
+  local.get $n
+  const 0
+  i.le
+  if (->)
+    do
+      local.get $acc
+      return
+  local.get $n
+  const -1
+  i.add
+  local.get $acc
+  local.get $n
+  dup
+  i.mul
+  i.add
+  return-call count
+  return
+"#;
+
+fn run_with(engine: CalxEngine, code: &str, pass: fn(&mut CalxFunc)) -> Result<Calx, CalxError> {
+  let fns = parse_program(code).map_err(CalxError::new_raw)?;
+  let mut vm = CalxVM::new_with_engine(fns, vec![], HashMap::new(), engine);
+  vm.preprocess(false).map_err(CalxError::new_raw)?;
+  for f in &mut vm.funcs {
+    pass(f);
+  }
+  vm.setup_top_frame().map_err(CalxError::new_raw)?;
+  vm.run(vec![]).into_result()
+}
+
+#[test]
+fn test_register_engine() -> Result<(), String> {
+  let expected = run_with(CalxEngine::Stack, CODE, |_| {}).map_err(|e| e.message)?;
+  assert_eq!(expected, Calx::I64(500500 + 6765 + 338350 + 2));
+
+  assert_eq!(run_with(CalxEngine::Register, CODE, |_| {}).map_err(|e| e.message)?, expected);
+  // fused instructions
+  assert_eq!(run_with(CalxEngine::Register, CODE, fuse_instrs).map_err(|e| e.message)?, expected);
+
+  let floats = r#"
+fn main (-> f64)
+  const 1.5
+  const 2.5
+  add
+  neg
+  const 0.5
+  div
+  return
+"#;
+  assert_eq!(
+    run_with(CalxEngine::Register, floats, |_| {}).map_err(|e| e.message)?,
+    Calx::F64(-8.0)
+  );
+
+  Ok(())
+}
+
+#[test]
+fn test_register_engine_error() {
+  let code = r#"
+fn main (-> i64)
+  call add
+    const 1
+    const true
+  return
+
+fn add (($a i64) ($b i64) -> i64)
+  local.get $a
+  local.get $b
+  i.add
+  return
+"#;
+  let expected = run_with(CalxEngine::Stack, code, |_| {}).expect_err("adding bool");
+  let e = run_with(CalxEngine::Register, code, |_| {}).expect_err("adding bool");
+  assert_eq!(e.message, expected.message);
+  assert_eq!(&*e.top_frame.name, "add");
+  assert_eq!(e.syntax_index, expected.syntax_index);
+  assert_eq!(e.top_frame.locals, vec![Calx::I64(1), Calx::Bool(true)]);
+}
+
+#[test]
+fn test_register_lowering_reused() -> Result<(), String> {
+  let code = r#"
+fn sq (($x i64) -> i64)
+  local.get $x
+  dup
+  i.mul
+  return
+"#;
+  let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![], HashMap::new(), CalxEngine::Register);
+  vm.preprocess(false)?;
+  for i in 0..100 {
+    assert_eq!(vm.call("sq", &[Calx::I64(i)]).map_err(|e| e.message)?, vec![Calx::I64(i * i)]);
+  }
+
+  // functions changed directly are lowered again
+  let mut f = vm.funcs[0].to_owned();
+  let mut instrs = (*f.instrs).to_owned();
+  instrs[2] = CalxInstr::IntAdd;
+  f.instrs = instrs.into();
+  vm.funcs[0] = f;
+  assert_eq!(vm.call("sq", &[Calx::I64(5)]).map_err(|e| e.message)?, vec![Calx::I64(10)]);
+
+  Ok(())
+}
+
+/// globals are verified against those VM starts with and those from `global.new`
+#[test]
+fn test_register_globals_limit() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  global.new
+  const 2
+  global.set 1
+  global.get 0
+  global.get 1
+  i.add
+  return
+"#;
+  let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![Calx::I64(1)], HashMap::new(), CalxEngine::Register);
+  vm.preprocess(false)?;
+  vm.setup_top_frame()?;
+  assert_eq!(vm.run(vec![]).into_result().map_err(|e| e.message)?, Calx::I64(3));
+
+  let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![], HashMap::new(), CalxEngine::Register);
+  vm.preprocess(false)?;
+  vm.setup_top_frame()?;
+  let e = vm.run(vec![]).into_result().expect_err("global beyond limit");
+  assert!(e.message.contains("unknown global index 1"), "{}", e.message);
+
+  Ok(())
+}
@@ -0,0 +1,54 @@
+//! run with `cargo test --features sync`
+#![cfg(feature = "sync")]
+#![allow(clippy::result_large_err)]
+
+use std::{collections::HashMap, sync::Arc, thread};
+
+use cirru_parser::{parse, Cirru};
+
+use calx_vm::{parse_function, Calx, CalxEngine, CalxFunc, CalxProgram, CalxVM};
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn test_types_cross_threads() {
+  assert_send_sync::<Calx>();
+  assert_send_sync::<CalxFunc>();
+  assert_send_sync::<CalxProgram>();
+  assert_send_sync::<CalxVM>();
+}
+
+#[test]
+fn test_program_shared_by_threads() -> Result<(), String> {
+  let code = r#"
+fn square (($n i64) -> i64)
+  local.get $n
+  local.get $n
+  i.mul
+  return
+"#;
+  let mut funcs: Vec<CalxFunc> = vec![];
+  for x in parse(code)? {
+    if let Cirru::List(ys) = x {
+      funcs.push(parse_function(&ys)?);
+    }
+  }
+  let program = Arc::new(CalxProgram::new(funcs, HashMap::new())?);
+
+  let workers: Vec<_> = (0..4)
+    .map(|i| {
+      let program = program.to_owned();
+      thread::spawn(move || {
+        let engine = if i % 2 == 0 { CalxEngine::Stack } else { CalxEngine::Register };
+        let mut vm = program.vm(vec![], engine);
+        vm.call("square", &[Calx::I64(i)]).map_err(|e| e.message)
+      })
+    })
+    .collect();
+  for (i, worker) in workers.into_iter().enumerate() {
+    let i = i as i64;
+    assert_eq!(worker.join().expect("worker finished")?, vec![Calx::I64(i * i)]);
+  }
+
+  Ok(())
+}
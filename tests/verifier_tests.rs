@@ -0,0 +1,144 @@
+use calx_vm::rc::Rc;
+use std::{collections::HashMap, fs};
+
+use calx_vm::{
+  log_calx_value, verify_func, verify_funcs, Calx, CalxFunc, CalxImport, CalxImportFn, CalxImportsDict, CalxInstr, CalxVM,
+  CalxVerifyErrorKind,
+};
+
+mod common;
+use common::{make_func, parse_program};
+
+fn verify_kinds(funcs: &[CalxFunc], globals_size: usize) -> Vec<CalxVerifyErrorKind> {
+  let imports = vec![CalxImport {
+    name: Rc::from("log"),
+    f: CalxImportFn::Plain(log_calx_value),
+    arity: 1,
+    sig: None,
+  }];
+  match verify_funcs(funcs, globals_size, &imports) {
+    Ok(()) => vec![],
+    Err(es) => es.into_iter().map(|e| e.kind).collect(),
+  }
+}
+
+/// programs from `preprocess` are expected to pass verification
+#[test]
+fn test_verify_demos() -> Result<(), String> {
+  for name in ["hello", "sum", "assert", "nested", "named", "recur", "fibonacci", "if", "fibo-if"] {
+    let contents = fs::read_to_string(format!("demos/{name}.cirru")).map_err(|e| e.to_string())?;
+    let fns = parse_program(&contents)?;
+    let mut imports: CalxImportsDict = HashMap::new();
+    imports.insert(Rc::from("log2"), (log_calx_value, 2));
+    let mut vm = CalxVM::new(fns, vec![], imports);
+    vm.preprocess(false)?;
+    if let Err(es) = vm.verify() {
+      return Err(format!("{name}: {}", es[0]));
+    }
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_verify_references() {
+  let funcs = vec![make_func(
+    "main",
+    0,
+    0,
+    vec![
+      CalxInstr::Call(4),
+      CalxInstr::GlobalGet(1),
+      CalxInstr::CallImport(1),
+      CalxInstr::Jmp(10),
+      CalxInstr::JmpOffset(-5),
+      CalxInstr::LocalGet(0),
+      CalxInstr::LocalSet(2),
+    ],
+  )];
+  assert_eq!(
+    verify_kinds(&funcs, 1),
+    vec![
+      CalxVerifyErrorKind::UnknownFunc(4),
+      CalxVerifyErrorKind::UnknownGlobal(1),
+      CalxVerifyErrorKind::UnknownImport(1),
+      CalxVerifyErrorKind::InvalidJump(10),
+      CalxVerifyErrorKind::InvalidJump(-1),
+      CalxVerifyErrorKind::UnknownLocal(0),
+      CalxVerifyErrorKind::UnknownLocal(2),
+    ]
+  );
+
+  // params and slots from `local.new` are locals
+  let funcs = vec![make_func(
+    "main",
+    1,
+    0,
+    vec![CalxInstr::LocalNew, CalxInstr::LocalGet(1), CalxInstr::LocalSet(0)],
+  )];
+  assert_eq!(verify_kinds(&funcs, 1), vec![]);
+
+  // function index out of range is reported
+  assert!(verify_func(1, &funcs, 1, &[]).is_err());
+
+  // slots from `global.new` are counted
+  let funcs = vec![make_func(
+    "main",
+    0,
+    0,
+    vec![CalxInstr::GlobalNew, CalxInstr::GlobalGet(1), CalxInstr::Drop],
+  )];
+  assert_eq!(verify_kinds(&funcs, 1), vec![]);
+}
+
+#[test]
+fn test_verify_stack_depth() {
+  // branches join with different stack depths
+  let funcs = vec![make_func(
+    "main",
+    0,
+    0,
+    vec![
+      CalxInstr::Const(Calx::Bool(true)),
+      CalxInstr::JmpIf(3),
+      CalxInstr::Const(Calx::I64(1)),
+      CalxInstr::Nop,
+    ],
+  )];
+  assert_eq!(
+    verify_kinds(&funcs, 0),
+    vec![CalxVerifyErrorKind::StackMismatch { expected: 0, found: 1 }]
+  );
+
+  let funcs = vec![make_func("main", 0, 0, vec![CalxInstr::Const(Calx::I64(1)), CalxInstr::IntAdd])];
+  assert_eq!(
+    verify_kinds(&funcs, 0),
+    vec![CalxVerifyErrorKind::StackUnderflow { expected: 2, found: 1 }]
+  );
+
+  let funcs = vec![
+    make_func(
+      "main",
+      0,
+      1,
+      vec![CalxInstr::Const(Calx::I64(1)), CalxInstr::Call(1), CalxInstr::Return],
+    ),
+    make_func(
+      "f",
+      1,
+      1,
+      vec![
+        CalxInstr::Const(Calx::I64(1)),
+        CalxInstr::Const(Calx::I64(2)),
+        CalxInstr::ReturnCall(1),
+      ],
+    ),
+  ];
+  assert_eq!(verify_kinds(&funcs, 0), vec![CalxVerifyErrorKind::ReturnCallMismatch(1)]);
+
+  let funcs = vec![make_func("main", 0, 1, vec![CalxInstr::Const(Calx::I64(1)), CalxInstr::Dup])];
+  assert_eq!(
+    verify_kinds(&funcs, 0),
+    vec![CalxVerifyErrorKind::ReturnSize { expected: 1, found: 2 }]
+  );
+}
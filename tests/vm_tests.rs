@@ -0,0 +1,1041 @@
+#![allow(clippy::result_large_err)]
+// imported functions take `&Vec<Calx>`
+#![allow(clippy::ptr_arg)]
+
+use calx_vm::rc::Rc;
+use std::{
+  collections::HashMap,
+  panic::{self, AssertUnwindSafe},
+  sync::atomic::{AtomicI64, Ordering},
+  thread,
+  time::Duration,
+};
+
+use cirru_parser::{parse, Cirru};
+
+use calx_vm::{
+  parse_import, Calx, CalxEngine, CalxError, CalxErrorKind, CalxExtern, CalxFuelCosts, CalxFuelOutcome, CalxImportDecl, CalxImportSig,
+  CalxImportsDict, CalxInstr, CalxProgram, CalxRunOutcome, CalxType, CalxVM,
+};
+
+mod common;
+use common::parse_program;
+
+fn add_values(xs: &Vec<Calx>) -> Result<Calx, CalxError> {
+  match (&xs[0], &xs[1]) {
+    (Calx::I64(a), Calx::I64(b)) => Ok(Calx::I64(a + b)),
+    (a, b) => Err(CalxError::new_raw(format!("expected i64 values, got {a} {b}"))),
+  }
+}
+
+fn concat_strs(xs: &Vec<Calx>) -> Result<Calx, CalxError> {
+  match (&xs[0], &xs[1]) {
+    (Calx::Str(a), Calx::Str(b)) => Ok(Calx::Str(format!("{a}{b}").into())),
+    (a, b) => Err(CalxError::new_raw(format!("expected strings, got {a} {b}"))),
+  }
+}
+
+fn negate_value(xs: &Vec<Calx>) -> Result<Calx, CalxError> {
+  match &xs[0] {
+    Calx::I64(a) => Ok(Calx::I64(-a)),
+    a => Err(CalxError::new_raw(format!("expected i64 value, got {a}"))),
+  }
+}
+
+#[test]
+fn test_call_import_by_index() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  local.new $acc
+  local.new $i
+  const 0
+  local.set $acc
+  const 0
+  local.set $i
+  block (->)
+    loop (->)
+      local.get $acc
+      const 3
+      call-import add
+      local.set $acc
+      local.get $i
+      const 1
+      i.add
+      local.set $i
+      local.get $i
+      const 10
+      i.lt
+      br-if 0
+  local.get $acc
+  call-import neg
+  return
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut imports: CalxImportsDict = HashMap::new();
+    imports.insert(Rc::from("neg"), (negate_value, 1));
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![], imports, engine);
+    // imports registered after `new` are resolved by `preprocess`
+    vm.imports.insert(Rc::from("add"), (add_values, 2));
+    vm.preprocess(false)?;
+    vm.setup_top_frame()?;
+
+    // indexes follow names of imports in order
+    let names: Vec<&str> = vm.import_table.iter().map(|x| &*x.name).collect();
+    assert_eq!(names, vec!["add", "neg"]);
+    assert!(vm.funcs[0].instrs.contains(&CalxInstr::CallImport(0)));
+    assert!(vm.funcs[0].instrs.contains(&CalxInstr::CallImport(1)));
+
+    assert_eq!(vm.run(vec![]).into_result().map_err(|e| e.message)?, Calx::I64(-30));
+  }
+
+  let mut vm = CalxVM::new(parse_program(code)?, vec![], HashMap::new());
+  assert_eq!(vm.preprocess(false), Err("missing imported function add".to_owned()));
+
+  Ok(())
+}
+
+#[test]
+fn test_locals_without_local_new() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  const 0
+  local.set $total
+  call square-sum
+    const 3
+    const 4
+  local.get $total
+  i.add
+  return
+
+fn square-sum (($a i64) ($b i64) -> i64)
+  local.get $a
+  dup
+  i.mul
+  local.set $sum
+  local.get $b
+  dup
+  i.mul
+  local.get $sum
+  i.add
+  return
+"#;
+  let fns = parse_program(code)?;
+  assert_eq!(fns[1].locals_size, 3);
+
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut vm = CalxVM::new_with_engine(fns.to_owned(), vec![], HashMap::new(), engine);
+    vm.preprocess(false)?;
+    vm.setup_top_frame()?;
+    assert_eq!(vm.run(vec![]).into_result().map_err(|e| e.message)?, Calx::I64(25));
+  }
+
+  Ok(())
+}
+
+const COUNT_CODE: &str = r#"
+fn main (-> i64)
+  const 0
+  local.set $i
+  block (->)
+    loop (->)
+      call inc
+        local.get $i
+      local.set $i
+      local.get $i
+      const 20
+      i.lt
+      br-if 0
+  local.get $i
+  return
+
+fn inc (($x i64) -> i64)
+  local.get $x
+  const 1
+  i.add
+  return
+"#;
+
+#[test]
+fn test_run_with_fuel() -> Result<(), String> {
+  let prepare = || -> Result<CalxVM, String> {
+    let mut vm = CalxVM::new(parse_program(COUNT_CODE)?, vec![], HashMap::new());
+    vm.preprocess(false)?;
+    vm.setup_top_frame()?;
+    Ok(vm)
+  };
+
+  let mut vm = prepare()?;
+  assert_eq!(vm.run_with_fuel(vec![], 0).map_err(|e| e.message)?, CalxFuelOutcome::OutOfFuel);
+  assert_eq!(vm.top_frame.pointer, 0);
+
+  // resuming in small steps reaches the same result as running at once
+  let mut rounds = 0;
+  let ret = loop {
+    rounds += 1;
+    match vm.resume(7).map_err(|e| e.message)? {
+      CalxFuelOutcome::Finished(v) => break v,
+      CalxFuelOutcome::OutOfFuel => assert!(vm.fuel < 10, "paused with {} fuel left", vm.fuel),
+      CalxFuelOutcome::Quit(code) => return Err(format!("unexpected quit {code}")),
+    }
+  };
+  assert_eq!(ret, prepare()?.run(vec![]).into_result().map_err(|e| e.message)?);
+  assert!(rounds > 20);
+
+  let mut vm = prepare()?;
+  assert_eq!(
+    vm.run_with_fuel(vec![], 10_000).map_err(|e| e.message)?,
+    CalxFuelOutcome::Finished(Calx::I64(20))
+  );
+  let default_used = 10_000 - vm.fuel;
+
+  // calls cost more when configured so
+  let mut vm = prepare()?;
+  vm.fuel_costs = CalxFuelCosts {
+    call: 100,
+    ..CalxFuelCosts::default()
+  };
+  assert_eq!(
+    vm.run_with_fuel(vec![], 10_000).map_err(|e| e.message)?,
+    CalxFuelOutcome::Finished(Calx::I64(20))
+  );
+  assert_eq!(10_000 - vm.fuel, default_used + 20 * 90);
+
+  Ok(())
+}
+
+const ENDLESS_CODE: &str = r#"
+fn main (-> i64)
+  const 0
+  local.set $i
+  block (->)
+    loop (->)
+      local.get $i
+      const 1
+      i.add
+      local.set $i
+      br 0
+  local.get $i
+  return
+"#;
+
+#[test]
+fn test_interrupt_handle() -> Result<(), String> {
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut vm = CalxVM::new_with_engine(parse_program(ENDLESS_CODE)?, vec![], HashMap::new(), engine);
+    vm.preprocess(false)?;
+    vm.setup_top_frame()?;
+
+    let handle = vm.interrupt_handle();
+    let trigger = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      handle.interrupt();
+    });
+    let e = vm.run(vec![]).into_result().expect_err("interrupted");
+    trigger.join().map_err(|_| "trigger thread failed")?;
+
+    assert_eq!(e.kind, CalxErrorKind::Interrupted);
+    assert_eq!(&*e.top_frame.name, "main");
+    // state at the stop is kept for inspection
+    match e.top_frame.locals[0] {
+      Calx::I64(i) => assert!(i > 0),
+      ref v => return Err(format!("unexpected local {v}")),
+    }
+
+    // flag stays set until cleared
+    assert!(vm.interrupt.is_interrupted());
+    vm.interrupt.clear();
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_timeout() -> Result<(), String> {
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut vm = CalxVM::new_with_engine(parse_program(ENDLESS_CODE)?, vec![], HashMap::new(), engine);
+    vm.preprocess(false)?;
+    vm.setup_top_frame()?;
+    vm.timeout = Some(Duration::from_millis(10));
+    vm.check_interval = 1;
+
+    let e = vm.run(vec![]).into_result().expect_err("timeout");
+    assert_eq!(e.kind, CalxErrorKind::Timeout);
+  }
+
+  let mut vm = CalxVM::new(parse_program(COUNT_CODE)?, vec![], HashMap::new());
+  vm.preprocess(false)?;
+  vm.setup_top_frame()?;
+  vm.timeout = Some(Duration::from_secs(60));
+  assert_eq!(vm.run(vec![]).into_result().map_err(|e| e.message)?, Calx::I64(20));
+
+  Ok(())
+}
+
+#[test]
+fn test_call_depth_and_stack_limits() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  call down
+    const 1000
+  return
+
+fn down (($n i64) -> i64)
+  local.get $n
+  const 0
+  i.le
+  if (->)
+    do
+      const 0
+      return
+  const 1
+  call down
+    i.add (local.get $n) (const -1)
+  i.add
+  return
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let prepare = || -> Result<CalxVM, String> {
+      let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![], HashMap::new(), engine);
+      vm.preprocess(false)?;
+      vm.setup_top_frame()?;
+      Ok(vm)
+    };
+
+    let mut vm = prepare()?;
+    assert_eq!(vm.run(vec![]).into_result().map_err(|e| e.message)?, Calx::I64(1000));
+
+    let mut vm = prepare()?;
+    vm.max_call_depth = 100;
+    let e = vm.run(vec![]).into_result().expect_err("too deep");
+    assert_eq!(e.kind, CalxErrorKind::CallDepthExceeded);
+    assert!(e.message.contains("max_call_depth 100"), "{}", e.message);
+    assert_eq!(e.backtrace.len(), 100);
+    assert_eq!(e.backtrace.first().map(|x| &**x), Some("down"));
+    assert_eq!(e.backtrace.last().map(|x| &**x), Some("main"));
+
+    // each frame of `down` leaves a value on stack before calling
+    let mut vm = prepare()?;
+    vm.max_stack_len = 200;
+    let e = vm.run(vec![]).into_result().expect_err("stack too long");
+    assert_eq!(e.kind, CalxErrorKind::StackOverflow);
+    assert!(e.message.contains("max_stack_len 200"), "{}", e.message);
+    assert_eq!(e.backtrace.last().map(|x| &**x), Some("main"));
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_memory_quota() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  const |abcd
+  local.set $s
+  const 0
+  local.set $i
+  block (->)
+    loop (->)
+      ;; strings created and dropped are not counted for long
+      const "|a string dropped right away"
+      drop
+      local.get $s
+      local.get $s
+      call-import concat
+      local.set $s
+      local.get $i
+      const 1
+      i.add
+      local.set $i
+      local.get $i
+      const 12
+      i.lt
+      br-if 0
+  local.get $i
+  return
+"#;
+  let prepare = |engine: CalxEngine, max_memory: Option<usize>| -> Result<CalxVM, String> {
+    let mut imports: CalxImportsDict = HashMap::new();
+    imports.insert(Rc::from("concat"), (concat_strs, 2));
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![], imports, engine);
+    vm.preprocess(false)?;
+    vm.setup_top_frame()?;
+    vm.max_memory = max_memory;
+    Ok(vm)
+  };
+
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    // 4 bytes doubled 12 times, while copies on stack are counted on their own
+    let mut vm = prepare(engine, Some(50_000))?;
+    assert_eq!(vm.run(vec![]).into_result().map_err(|e| e.message)?, Calx::I64(12));
+
+    let mut vm = prepare(engine, Some(10_000))?;
+    let e = vm.run(vec![]).into_result().expect_err("memory exceeded");
+    assert_eq!(e.kind, CalxErrorKind::MemoryExceeded);
+    assert!(e.message.contains("max_memory 10000"), "{}", e.message);
+  }
+
+  let mut vm = prepare(CalxEngine::Stack, None)?;
+  vm.run(vec![]).into_result().map_err(|e| e.message)?;
+  let usage = vm.memory_usage();
+  assert_eq!(usage.locals, 4 << 12);
+  assert_eq!(usage.total(), usage.locals + usage.stack);
+
+  Ok(())
+}
+
+/// strings created in every iteration keep usage just below the limit for a long run
+#[test]
+fn test_memory_quota_steady() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  const 0
+  local.set $i
+  block (->)
+    loop (->)
+      const |0123456789
+      const |0123456789
+      call-import concat
+      local.set $s
+      local.get $i
+      const 1
+      i.add
+      local.set $i
+      local.get $i
+      const 10000
+      i.lt
+      br-if 0
+  local.get $i
+  return
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    for (max_memory, ok) in [(60, true), (30, false)] {
+      let mut imports: CalxImportsDict = HashMap::new();
+      imports.insert(Rc::from("concat"), (concat_strs, 2));
+      let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![], imports, engine);
+      vm.preprocess(false)?;
+      vm.setup_top_frame()?;
+      vm.max_memory = Some(max_memory);
+      match vm.run(vec![]) {
+        CalxRunOutcome::Returned(v) => assert!(ok && v == Calx::I64(10000), "{engine:?} {max_memory}"),
+        CalxRunOutcome::Trap(kind, _) => assert!(!ok && kind == CalxErrorKind::MemoryExceeded, "{engine:?} {max_memory}"),
+        CalxRunOutcome::Quit(code) => return Err(format!("unexpected quit {code}")),
+      }
+    }
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_quit_and_unreachable() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  local.get 0
+  const 1
+  i.eq
+  if (->)
+    do
+      quit 3
+  local.get 0
+  const 2
+  i.eq
+  if (->)
+    do
+      unreachable
+  const 10
+  return
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![], HashMap::new(), engine);
+    vm.preprocess(false)?;
+    vm.setup_top_frame()?;
+
+    assert_eq!(vm.clone().run(vec![Calx::I64(1)]), CalxRunOutcome::Quit(3));
+    let e = vm.clone().run(vec![Calx::I64(1)]).into_result().expect_err("quit");
+    assert_eq!(e.kind, CalxErrorKind::Quit(3));
+    assert_eq!(vm.clone().run(vec![Calx::I64(0)]), CalxRunOutcome::Returned(Calx::I64(10)));
+    match vm.run(vec![Calx::I64(2)]) {
+      CalxRunOutcome::Trap(kind, e) => {
+        assert_eq!(kind, CalxErrorKind::Unreachable);
+        assert_eq!(e.kind, kind);
+      }
+      outcome => return Err(format!("expected trap, got {outcome:?}")),
+    }
+  }
+
+  let mut vm = CalxVM::new(parse_program(code)?, vec![], HashMap::new());
+  vm.preprocess(false)?;
+  vm.setup_top_frame()?;
+  assert_eq!(
+    vm.run_with_fuel(vec![Calx::I64(1)], 100).map_err(|e| e.message)?,
+    CalxFuelOutcome::Quit(3)
+  );
+
+  Ok(())
+}
+
+#[test]
+fn test_call_by_name() -> Result<(), String> {
+  // a library without `main`
+  let code = r#"
+fn fibo (($n i64) -> i64)
+  local.get $n
+  const 2
+  i.lt
+  if (-> i64)
+    do
+      const 1
+    do
+      local.get $n
+      const -1
+      i.add
+      call fibo
+      local.get $n
+      const -2
+      i.add
+      call fibo
+      i.add
+  return
+
+fn div-rem (($a i64) ($b i64) -> i64 i64)
+  local.get $a
+  local.get $b
+  i.div
+  local.get $a
+  local.get $b
+  i.rem
+  return
+
+fn store (($a i64))
+  local.get $a
+  global.set 0
+  return
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![Calx::Nil], HashMap::new(), engine);
+    vm.preprocess(false)?;
+
+    assert_eq!(vm.call("fibo", &[Calx::I64(10)]).map_err(|e| e.message)?, vec![Calx::I64(89)]);
+    assert_eq!(vm.call("fibo", &[Calx::I64(1)]).map_err(|e| e.message)?, vec![Calx::I64(1)]);
+    assert_eq!(
+      vm.call("div-rem", &[Calx::I64(17), Calx::I64(5)]).map_err(|e| e.message)?,
+      vec![Calx::I64(3), Calx::I64(2)]
+    );
+    // `return` without values
+    assert_eq!(vm.call("store", &[Calx::I64(4)]).map_err(|e| e.message)?, vec![]);
+    assert_eq!(vm.globals, vec![Calx::I64(4)]);
+
+    assert!(vm.call("fibo", &[]).is_err());
+    assert!(vm.call("fibo", &[Calx::F64(1.0)]).is_err());
+    assert!(vm.call("missing", &[]).is_err());
+    assert!(vm.call("div-rem", &[Calx::I64(1), Calx::I64(0)]).is_err());
+    assert!(matches!(vm.run(vec![]), CalxRunOutcome::Trap(..)));
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_reuse_with_reset() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  global.get 0
+  const 1
+  i.add
+  global.set 0
+  global.get 0
+  return
+
+fn fail ()
+  call boom
+
+fn boom ()
+  unreachable
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![Calx::I64(0)], HashMap::new(), engine);
+    vm.preprocess(false)?;
+
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(1)));
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(2)));
+    // frames of an interrupted call are dropped before next run
+    assert!(vm.call("fail", &[]).is_err());
+    assert_eq!(vm.call("main", &[]).map_err(|e| e.message)?, vec![Calx::I64(3)]);
+
+    vm.reset();
+    assert!(!vm.finished);
+    assert!(vm.frames.is_empty() && vm.stack.is_empty());
+    assert_eq!(vm.globals, vec![Calx::I64(3)]);
+
+    vm.reset_globals();
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(1)));
+  }
+
+  Ok(())
+}
+
+/// closures left marked as running by a panic can be called again after `reset`
+#[test]
+fn test_reset_after_import_panic() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  call-import next
+  return
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![], HashMap::new(), engine);
+    let mut count = 0;
+    vm.add_import("next", 0, move |_, _| {
+      count += 1;
+      if count == 1 {
+        panic!("failed in host");
+      }
+      Ok(Calx::I64(count))
+    });
+    vm.preprocess(false)?;
+
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| vm.run(vec![]))).is_err());
+    vm.reset();
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(2)));
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_snapshot_and_restore() -> Result<(), String> {
+  let mut vm = CalxVM::new(parse_program(COUNT_CODE)?, vec![Calx::Str(Rc::from("g"))], HashMap::new());
+  vm.preprocess(false)?;
+  vm.fuel_costs = CalxFuelCosts {
+    call: 3,
+    ..CalxFuelCosts::default()
+  };
+  assert_eq!(vm.run_with_fuel(vec![], 5).map_err(|e| e.message)?, CalxFuelOutcome::OutOfFuel);
+
+  // every pause is persisted and continued in a restored VM
+  let mut paused_in_call = false;
+  let ret = loop {
+    let bytes = vm.snapshot();
+    vm = CalxVM::restore(&bytes, HashMap::new())?;
+    assert_eq!(vm.snapshot(), bytes);
+    paused_in_call = paused_in_call || !vm.frames.is_empty();
+    match vm.resume(4).map_err(|e| e.message)? {
+      CalxFuelOutcome::Finished(v) => break v,
+      CalxFuelOutcome::OutOfFuel => {}
+      CalxFuelOutcome::Quit(code) => return Err(format!("unexpected quit {code}")),
+    }
+  };
+  assert_eq!(ret, Calx::I64(20));
+  assert!(paused_in_call);
+  assert_eq!(vm.globals, vec![Calx::Str(Rc::from("g"))]);
+  assert_eq!(vm.fuel_costs.call, 3);
+
+  // instructions refer to imports by index, so names have to match
+  let mut imports: CalxImportsDict = HashMap::new();
+  imports.insert(Rc::from("add"), (add_values, 2));
+  assert!(CalxVM::restore(&vm.snapshot(), imports).is_err());
+  assert!(CalxVM::restore(&[1, 2, 3], HashMap::new()).is_err());
+
+  Ok(())
+}
+
+/// decoded snapshots are checked, broken bytes are rejected or run without panics
+#[test]
+fn test_restore_invalid_snapshot() -> Result<(), String> {
+  let mut vm = CalxVM::new(parse_program(COUNT_CODE)?, vec![], HashMap::new());
+  vm.preprocess(false)?;
+  assert_eq!(vm.run_with_fuel(vec![], 30).map_err(|e| e.message)?, CalxFuelOutcome::OutOfFuel);
+  let bytes = vm.snapshot();
+
+  for size in 0..bytes.len() {
+    assert!(CalxVM::restore(&bytes[..size], HashMap::new()).is_err(), "truncated to {size}");
+  }
+  for idx in 0..bytes.len() {
+    for v in [0, 1, 0x7f, 0xff] {
+      let mut broken = bytes.to_owned();
+      broken[idx] = v;
+      if let Ok(mut restored) = CalxVM::restore(&broken, HashMap::new()) {
+        restored.fuel_costs = CalxFuelCosts::default();
+        let _ = restored.resume(200);
+      }
+    }
+  }
+
+  // state that does not fit functions is rejected
+  let mut broken = vm.clone();
+  broken.top_frame.pointer = 100;
+  assert!(CalxVM::restore(&broken.snapshot(), HashMap::new()).is_err());
+  let mut broken = vm.clone();
+  broken.top_frame.locals.extend(vec![Calx::Nil; 4]);
+  assert!(CalxVM::restore(&broken.snapshot(), HashMap::new()).is_err());
+  let mut broken = vm.clone();
+  broken.funcs[1].instrs = Rc::new(vec![CalxInstr::IntAdd, CalxInstr::Return]);
+  assert!(CalxVM::restore(&broken.snapshot(), HashMap::new()).is_err());
+  let mut broken = vm.clone();
+  broken.stack.clear();
+  broken.top_frame.initial_stack_size = 1;
+  assert!(CalxVM::restore(&broken.snapshot(), HashMap::new()).is_err());
+
+  Ok(())
+}
+
+#[test]
+fn test_program_creates_vms() -> Result<(), String> {
+  let program = CalxProgram::new(parse_program(COUNT_CODE)?, HashMap::new())?;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut vm = program.vm(vec![], engine);
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(20)));
+    assert_eq!(vm.call("inc", &[Calx::I64(1)]).map_err(|e| e.message)?, vec![Calx::I64(2)]);
+  }
+  // preprocessing errors are reported when creating the program
+  assert!(CalxProgram::new(parse_program("fn main ()\n  call missing\n")?, HashMap::new()).is_err());
+
+  Ok(())
+}
+
+#[test]
+fn test_closure_imports() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  call-import next
+  call-import next
+  i.add
+  call-import emit
+  drop
+  call-import next
+  return
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut imports: CalxImportsDict = HashMap::new();
+    // replaced by closure of the same name
+    imports.insert(Rc::from("emit"), (negate_value, 1));
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![Calx::Nil], imports, engine);
+    let mut count = 0;
+    vm.add_import("next", 0, move |_, _| {
+      count += 1;
+      Ok(Calx::I64(count))
+    });
+    vm.add_import("emit", 1, |ctx, xs| {
+      ctx.globals_mut()[0] = xs[0].to_owned();
+      match ctx.user_data::<Vec<Calx>>() {
+        Some(buffer) => buffer.push(xs[0].to_owned()),
+        None => return Err(CalxError::new_raw("missing buffer".to_owned())),
+      }
+      Ok(Calx::I64(0))
+    });
+    vm.preprocess(false)?;
+    vm.set_user_data::<Vec<Calx>>(vec![]);
+
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(3)));
+    assert_eq!(vm.globals, vec![Calx::I64(3)]);
+    assert_eq!(vm.user_data::<Vec<Calx>>(), Some(&vec![Calx::I64(3)]));
+    assert_eq!(vm.user_data::<String>(), None);
+
+    // state of closures is kept between runs, user data stays with the VM
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(6)));
+    assert_eq!(vm.user_data::<Vec<Calx>>().map(|xs| xs.len()), Some(2));
+    assert!(vm.clone().run(vec![]).into_result().is_err());
+
+    // closures are added again after restoring
+    let mut restored = CalxVM::restore(&vm.snapshot(), HashMap::new())?;
+    assert!(restored.run(vec![]).into_result().is_err());
+    restored.add_import("next", 0, |_, _| Ok(Calx::I64(1)));
+    restored.add_import("emit", 1, |_, xs| Ok(xs[0].to_owned()));
+    assert_eq!(restored.run(vec![]), CalxRunOutcome::Returned(Calx::I64(1)));
+  }
+
+  Ok(())
+}
+
+fn parse_import_decls(code: &str) -> Result<Vec<CalxImportDecl>, String> {
+  let mut decls = vec![];
+  for x in parse(code)? {
+    if let Cirru::List(ys) = x {
+      decls.push(parse_import(&ys)?);
+    }
+  }
+  Ok(decls)
+}
+
+#[test]
+fn test_typed_imports() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  const 1
+  call-import emit
+  const 2
+  const 3
+  call-import add
+  return
+"#;
+  let decls = parse_import_decls("import add (i64 i64 -> i64)\nimport emit (i64 ->)")?;
+  assert_eq!(decls[1].sig.to_string(), "(I64 ->)");
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut imports: CalxImportsDict = HashMap::new();
+    imports.insert(Rc::from("add"), (add_values, 2));
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![Calx::Nil], imports, engine);
+    vm.import_decls = decls.to_owned();
+    // emit pushes nothing, as declared
+    vm.add_typed_import(
+      "emit",
+      CalxImportSig {
+        params: vec![CalxType::I64],
+        rets: vec![],
+      },
+      |ctx, xs| {
+        ctx.globals_mut()[0] = xs[0].to_owned();
+        Ok(Calx::Nil)
+      },
+    );
+    vm.preprocess(false)?;
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(5)));
+    assert_eq!(vm.globals, vec![Calx::I64(1)]);
+  }
+
+  // arguments are checked when called
+  let code = r#"
+fn main (-> i64)
+  const |a
+  const 2
+  call-import add
+  return
+"#;
+  let mut imports: CalxImportsDict = HashMap::new();
+  imports.insert(Rc::from("add"), (concat_strs, 2));
+  let mut vm = CalxVM::new(parse_program(code)?, vec![], imports);
+  vm.import_decls = parse_import_decls("import add (i64 i64 -> i64)")?;
+  vm.preprocess(false)?;
+  assert!(vm.run(vec![]).into_result().is_err());
+
+  // declarations not fitting host registration are reported in preprocess
+  let mut imports: CalxImportsDict = HashMap::new();
+  imports.insert(Rc::from("add"), (add_values, 2));
+  for (decl, sig) in [
+    ("import add (i64 -> i64)", None),
+    ("import sub (i64 i64 -> i64)", None),
+    ("import add (i64 i64 -> i64 i64)", None),
+    ("import add (i64 i64 -> i64)", Some(vec![CalxType::F64, CalxType::F64])),
+  ] {
+    let mut vm = CalxVM::new(parse_program(code)?, vec![], imports.to_owned());
+    vm.import_decls = parse_import_decls(decl)?;
+    if let Some(params) = sig {
+      vm.set_import_sig(
+        "add",
+        CalxImportSig {
+          params,
+          rets: vec![CalxType::F64],
+        },
+      );
+    }
+    assert!(vm.preprocess(false).is_err(), "expected error from {decl}");
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_calls_from_imports() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  const 10
+  const 4
+  call-import pick
+  const 3
+  call-import fold
+  i.add
+  call-import nest
+  return
+
+fn less (($a i64) ($b i64) -> bool)
+  local.get $a
+  local.get $b
+  i.lt
+  return
+
+fn step (($acc i64) ($x i64) -> i64)
+  local.get $acc
+  local.get $x
+  i.mul
+  return
+
+fn fails (-> i64)
+  unreachable
+
+fn again ()
+  call-import nest
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![Calx::Nil], HashMap::new(), engine);
+    // smaller of two values, with a comparator from Calx, a failing call does not break the caller
+    vm.add_import("pick", 2, |ctx, xs| {
+      assert!(ctx.call("fails", &[]).is_err());
+      match ctx.call("less", xs)?.as_slice() {
+        [Calx::Bool(true)] => Ok(xs[0].to_owned()),
+        _ => Ok(xs[1].to_owned()),
+      }
+    });
+    // product of 1..=n with `step`
+    vm.add_import("fold", 1, |ctx, xs| {
+      let mut acc = Calx::I64(1);
+      if let Calx::I64(n) = xs[0] {
+        for i in 1..=n {
+          acc = ctx.call("step", &[acc, Calx::I64(i)])?.remove(0);
+        }
+      }
+      Ok(acc)
+    });
+    // a closure being called can not be entered again
+    vm.add_typed_import(
+      "nest",
+      CalxImportSig {
+        params: vec![],
+        rets: vec![],
+      },
+      |ctx, _| {
+        if let Err(e) = ctx.call("again", &[]) {
+          ctx.globals_mut()[0] = Calx::Str(e.message.as_str().into());
+        }
+        Ok(Calx::Nil)
+      },
+    );
+    vm.preprocess(false)?;
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(10)));
+    assert!(matches!(&vm.globals[0], Calx::Str(s) if s.contains("already running")));
+    let values = vm.call("less", &[Calx::I64(1), Calx::I64(2)]).map_err(|e| e.message)?;
+    assert_eq!(values, vec![Calx::Bool(true)]);
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_extern_values() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  local.new $c
+  call-import counter
+  local.set $c
+  local.get $c
+  call-import bump
+  drop
+  local.get $c
+  call-import bump
+  return
+"#;
+  let decls = parse_import_decls("import counter (-> extern.counter)\nimport bump (extern.counter -> i64)")?;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![Calx::Nil], HashMap::new(), engine);
+    vm.import_decls = decls.to_owned();
+    vm.add_import("counter", 0, |_, _| Ok(Calx::Extern(CalxExtern::new("counter", AtomicI64::new(0)))));
+    vm.add_import("bump", 1, |ctx, xs| match &xs[0] {
+      Calx::Extern(x) => match x.downcast_ref::<AtomicI64>() {
+        Some(n) => {
+          ctx.globals_mut()[0] = xs[0].to_owned();
+          Ok(Calx::I64(n.fetch_add(1, Ordering::Relaxed) + 1))
+        }
+        None => Err(CalxError::new_raw(format!("expected counter, got {x}"))),
+      },
+      x => Err(CalxError::new_raw(format!("expected extern, got {x}"))),
+    });
+    vm.preprocess(false)?;
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(2)));
+    assert_eq!(vm.globals[0].to_string(), "<extern counter>");
+    assert!(vm.globals[0].typed_as(CalxType::Extern(None)));
+
+    // values are shared by clones and compared by identity
+    let c = CalxExtern::new("counter", AtomicI64::new(0));
+    assert_eq!(Calx::Extern(c.to_owned()), Calx::Extern(c.to_owned()));
+    assert_ne!(Calx::Extern(c), vm.globals[0]);
+
+    // tags are checked with types of imports
+    vm.add_import("counter", 0, |_, _| Ok(Calx::Extern(CalxExtern::new("file", ()))));
+    assert!(vm.run(vec![]).into_result().is_err());
+  }
+
+  // host values are not kept in snapshots, restored values keep identities
+  let c = Calx::Extern(CalxExtern::new("counter", 1_i64));
+  let globals = vec![c.to_owned(), Calx::Extern(CalxExtern::new("counter", 1_i64)), c];
+  let mut vm = CalxVM::new(parse_program(code)?, globals, HashMap::new());
+  vm.import_decls = decls;
+  let restored = CalxVM::restore(&vm.snapshot(), HashMap::new())?;
+  match &restored.globals[0] {
+    Calx::Extern(x) => assert!(x.is_detached() && &*x.tag == "counter"),
+    x => return Err(format!("expected extern, got {x}")),
+  }
+  assert_ne!(restored.globals[0], restored.globals[1]);
+  assert_eq!(restored.globals[0], restored.globals[2]);
+
+  Ok(())
+}
+
+#[test]
+fn test_add_import_after_preprocess() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  const 1
+  const 1
+  call-import b
+  return
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    let mut imports: CalxImportsDict = HashMap::new();
+    imports.insert(Rc::from("b"), (add_values, 2));
+    let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![], imports.to_owned(), engine);
+    vm.preprocess(false)?;
+
+    // a name sorted before `b` does not move it
+    vm.add_import("a", 2, |_, _| Ok(Calx::I64(99)));
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(2)));
+
+    // appended names are kept in snapshots
+    let mut restored = CalxVM::restore(&vm.snapshot(), imports)?;
+    restored.add_import("a", 2, |_, _| Ok(Calx::I64(99)));
+    assert_eq!(restored.run(vec![]), CalxRunOutcome::Returned(Calx::I64(2)));
+
+    vm.preprocess(false)?;
+    assert_eq!(vm.run(vec![]), CalxRunOutcome::Returned(Calx::I64(2)));
+  }
+
+  Ok(())
+}
+
+#[test]
+fn test_quit_in_call_from_import() -> Result<(), String> {
+  let code = r#"
+fn main (-> i64)
+  call-import guard
+  const 1
+  global.set 0
+  const 0
+  return
+
+fn q ()
+  quit 3
+"#;
+  for engine in [CalxEngine::Stack, CalxEngine::Register] {
+    for propagate in [true, false] {
+      let mut vm = CalxVM::new_with_engine(parse_program(code)?, vec![Calx::I64(0)], HashMap::new(), engine);
+      vm.add_typed_import(
+        "guard",
+        CalxImportSig {
+          params: vec![],
+          rets: vec![],
+        },
+        move |ctx, _| match ctx.call("q", &[]) {
+          Err(e) if propagate => Err(e),
+          Err(e) => {
+            assert_eq!(e.kind, CalxErrorKind::Quit(3));
+            Ok(Calx::Nil)
+          }
+          Ok(_) => Err(CalxError::new_raw("expected quit".to_owned())),
+        },
+      );
+      vm.preprocess(false)?;
+      assert_eq!(vm.run(vec![]), CalxRunOutcome::Quit(3));
+      // caller stops at the import
+      assert_eq!(vm.globals, vec![Calx::I64(0)]);
+    }
+  }
+
+  Ok(())
+}